@@ -0,0 +1,390 @@
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use sacp::link::ProxyToConductor;
+use sacp::schema::{
+    ContentBlock, ContentChunk, PromptRequest, SessionId, SessionNotification, SessionUpdate,
+    StopReason, TextContent,
+};
+use sacp::{AgentPeer, ClientPeer, JrConnectionCx};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+use crate::baseline::{self, BaselineCommand, BaselineComparison};
+use crate::cargo_command::{
+    CargoCommandJsonResult, CargoCommandOptions, CommandStatus, execute_cargo_command,
+    fit_to_budget,
+};
+use crate::config::{AutoCheckConfig, Config, Stage};
+use crate::extra_args::{self, STAGE_COMMANDS};
+use crate::session::{AutoCheckOutcome, SessionState};
+use crate::{metadata, progress};
+
+/// Run the verification pipeline (by default just `cargo check`) after a turn in which the
+/// agent edited Rust files and, if a stage fails, prompt the agent to fix the problems. The
+/// follow-up turn is checked the same way, so this loops until every stage passes, the agent
+/// stops making progress, or the configured number of consecutive follow-ups have been sent.
+pub(crate) fn check_after_turn(
+    cx: JrConnectionCx<ProxyToConductor>,
+    session_id: SessionId,
+    session: Arc<SessionState>,
+) -> BoxFuture<Result<(), sacp::Error>> {
+    // Boxed because the follow-up handler awaits this function recursively
+    Box::pin(async move {
+        let unchecked_rs_files = session.take_unchecked_rs_files();
+        if unchecked_rs_files.is_empty() {
+            return Ok(());
+        }
+        let cwd = session.cwd().await;
+
+        let mut config = match session.config(cwd.as_deref().map(Path::new)) {
+            Ok(config) => config,
+            Err(err) => {
+                notify_client(
+                    &cx,
+                    session_id.clone(),
+                    format!("{err:#}. Falling back to the default automatic check.\n"),
+                )?;
+                Config::default()
+            }
+        };
+        if !config.auto_check.enabled {
+            return Ok(());
+        }
+        if let Err(err) = check_stages(&config.auto_check.stages, cwd.as_deref()).await {
+            notify_client(
+                &cx,
+                session_id.clone(),
+                format!("{err:#}. Falling back to the default automatic check.\n"),
+            )?;
+            config.auto_check.stages = AutoCheckConfig::default().stages;
+        }
+        let max_followups = config.auto_check.max_followups;
+
+        let Some(mut failure) =
+            run_pipeline(&cx, &config, &session, &unchecked_rs_files, cwd.clone()).await?
+        else {
+            session.reset_auto_check();
+            return Ok(());
+        };
+        let failed_stage = &failure.stages[failure.failed];
+
+        match session.record_auto_check_failure(
+            failed_stage,
+            &failure.result.diagnostics,
+            max_followups,
+        ) {
+            AutoCheckOutcome::FollowUp => {}
+            AutoCheckOutcome::NoProgress => {
+                return notify_client(
+                    &cx,
+                    session_id,
+                    indoc::formatdoc! {"
+                        `{failed_stage}` still fails with the same errors as before the last automatic follow-up. Not prompting the agent again.
+                    "},
+                );
+            }
+            // There were no follow-ups to report on
+            AutoCheckOutcome::BudgetExhausted if max_followups == 0 => return Ok(()),
+            AutoCheckOutcome::BudgetExhausted => {
+                return notify_client(
+                    &cx,
+                    session_id,
+                    indoc::formatdoc! {"
+                        `{failed_stage}` still fails after {max_followups} automatic follow-up(s). Not prompting the agent again.
+                    "},
+                );
+            }
+        }
+
+        // Stored so the agent can page through what does not fit in the prompt
+        let root = metadata::workspace_root(cwd.as_deref()).await.ok();
+        failure.result.result_id = Some(session.results().insert(&failure.result, root));
+        fit_to_budget(&mut failure.result, &config.output);
+        let truncation = truncation_note(&failure.result);
+        let json = serde_json::to_string(&failure.result)?;
+        let edited_files = unchecked_rs_files
+            .iter()
+            .map(|p| format!("- {}", p.display()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let stages = failure.summary();
+        let content = ContentBlock::Text(TextContent::new(indoc::formatdoc! {"
+            Automatic verification has been run after you edited the following files:
+
+            {edited_files}
+
+            {stages}
+
+            `{failed_stage}` failed with the following output. You may wish to fix the problems.{truncation}
+
+            {json}
+        "}));
+        cx.send_request_to(
+            AgentPeer,
+            PromptRequest::new(session_id.clone(), vec![content]),
+        )
+        .on_receiving_result({
+            let cx = cx.clone();
+            let session_id = session_id.clone();
+            let session = session.clone();
+            async move |res| match res {
+                Ok(res) if res.stop_reason == StopReason::EndTurn => {
+                    check_after_turn(cx, session_id, session).await
+                }
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    tracing::warn!(?err, "automatic follow-up prompt failed");
+                    Ok(())
+                }
+            }
+        })?;
+
+        let content = ContentBlock::Text(TextContent::new(indoc::formatdoc! {"
+            Automatic verification has been run and `{failed_stage}` failed with the following output (omitted). You may wish to fix the problems.
+        "}));
+        cx.send_notification_to(
+            ClientPeer,
+            SessionNotification::new(
+                session_id,
+                SessionUpdate::UserMessageChunk(ContentChunk::new(content)),
+            ),
+        )?;
+
+        Ok(())
+    })
+}
+
+/// What was left out of `result` to fit the output budget and how to get it, as a sentence
+/// to append to the follow-up prompt; empty if nothing was
+fn truncation_note(result: &CargoCommandJsonResult) -> String {
+    let (Some(truncation), Some(result_id)) = (&result.truncated, result.result_id) else {
+        return String::new();
+    };
+    let collapsed: usize = truncation.collapsed_lints.values().sum();
+    let elided = [
+        (truncation.elided_diagnostics + collapsed, "diagnostics"),
+        (truncation.elided_stdout_bytes, "bytes of stdout"),
+        (truncation.elided_stderr_bytes, "bytes of stderr"),
+    ]
+    .into_iter()
+    .filter(|(count, _)| *count > 0)
+    .map(|(count, what)| format!("{count} {what}"))
+    .collect::<Vec<_>>();
+    let elided = match elided.split_last() {
+        None => return String::new(),
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} and {last}", rest.join(", ")),
+    };
+    format!(
+        " To keep it short, {elided} were left out; `get_cargo_result` with `result_id` {result_id} pages through all of it."
+    )
+}
+
+/// The first stage of the pipeline that failed, along with the stages around it
+struct PipelineFailure {
+    stages: Vec<String>,
+    failed: usize,
+    result: CargoCommandJsonResult,
+}
+
+impl PipelineFailure {
+    /// One line per stage saying whether it passed, failed or was skipped
+    fn summary(&self) -> String {
+        self.stages
+            .iter()
+            .enumerate()
+            .map(|(i, stage)| {
+                let status = match i.cmp(&self.failed) {
+                    Ordering::Less => "passed",
+                    Ordering::Equal => "failed",
+                    Ordering::Greater => "skipped",
+                };
+                format!("- `{stage}`: {status}")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Fail if a stage runs something other than [`STAGE_COMMANDS`] or has arguments that
+/// `extra_args` would reject. The stages can come from the project's configuration, which the
+/// agent can edit, and they run without asking the user.
+async fn check_stages(stages: &[Stage], cwd: Option<&str>) -> anyhow::Result<()> {
+    for stage in stages {
+        if !STAGE_COMMANDS.contains(&stage.command.as_str()) {
+            anyhow::bail!(
+                "`{}` cannot run as an automatic check; stages can only run {}",
+                stage.label(),
+                STAGE_COMMANDS.join(", ")
+            );
+        }
+        let args: Vec<&str> = stage.args.iter().map(String::as_str).collect();
+        let paths = extra_args::check("auto_check", &args)?;
+        if !paths.is_empty() {
+            let root = metadata::workspace_root(cwd).await?;
+            extra_args::check_paths("auto_check", &paths, Path::new(cwd.unwrap_or(".")), &root)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run each stage in order, stopping at the first one that fails or is cancelled
+async fn run_pipeline(
+    cx: &JrConnectionCx<ProxyToConductor>,
+    config: &Config,
+    session: &SessionState,
+    edited_files: &BTreeSet<PathBuf>,
+    cwd: Option<String>,
+) -> Result<Option<PipelineFailure>, sacp::Error> {
+    let stages = &config.auto_check.stages;
+    for (i, stage) in stages.iter().enumerate() {
+        let args = stage.args_for(edited_files);
+        let options = CargoCommandOptions {
+            timeout: Some(
+                stage
+                    .timeout_secs
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|| config.timeout_for("auto_check")),
+            ),
+            cancellation: Some(session.cancellation_token()),
+            progress: session
+                .session_id()
+                .filter(|_| session.progress_updates())
+                .map(|session_id| {
+                    progress::mirror_to_client(cx.clone(), session_id, stage.label())
+                }),
+            toolchain: session.toolchain().await,
+            ..Default::default()
+        };
+        let mut result = execute_cargo_command(
+            &stage.command,
+            args.iter().map(|a| a.as_str()).collect(),
+            cwd.clone(),
+            !stage.uses_json(),
+            options,
+        )
+        .await?;
+        // The user cancelled the turn, so don't prompt the agent about it
+        if result.status == CommandStatus::Cancelled {
+            return Ok(None);
+        }
+        if stage.uses_json()
+            && let Some(command) = BaselineCommand::for_subcommand(&stage.command)
+            && let Some(baseline) = session.baseline(
+                command,
+                &args.iter().map(|a| a.as_str()).collect::<Vec<_>>(),
+            )
+        {
+            let comparison = BaselineComparison::apply(&baseline, &mut result);
+            // Every error it reported was already there before the agent started
+            let only_preexisting = comparison.unchanged > 0
+                && !baseline::has_new_errors(&baseline, &result.diagnostics);
+            result.baseline = Some(comparison);
+            if only_preexisting {
+                continue;
+            }
+        }
+        if result.exit_code != Some(0) {
+            return Ok(Some(PipelineFailure {
+                stages: stages.iter().map(Stage::label).collect(),
+                failed: i,
+                result,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Tell the user why the automatic check loop stopped
+fn notify_client(
+    cx: &JrConnectionCx<ProxyToConductor>,
+    session_id: SessionId,
+    text: String,
+) -> Result<(), sacp::Error> {
+    let content = ContentBlock::Text(TextContent::new(text));
+    cx.send_notification_to(
+        ClientPeer,
+        SessionNotification::new(
+            session_id,
+            SessionUpdate::AgentMessageChunk(ContentChunk::new(content)),
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo_command::OutputBudget;
+    use crate::diagnostic::{Diagnostic, DiagnosticLevel};
+
+    #[test]
+    fn test_truncation_note() {
+        let diagnostics = (0..50)
+            .map(|i| {
+                Diagnostic::for_test(DiagnosticLevel::Warning, &format!("unused {i}"))
+                    .with_code("dead_code")
+            })
+            .collect();
+        let mut result = CargoCommandJsonResult {
+            stderr: "x".repeat(10_000),
+            result_id: Some(3),
+            ..CargoCommandJsonResult::for_test("cargo check", diagnostics)
+        };
+        assert_eq!(truncation_note(&result), "");
+
+        fit_to_budget(
+            &mut result,
+            &OutputBudget {
+                max_bytes: Some(2_000),
+                max_tokens: None,
+            },
+        );
+        assert_eq!(
+            truncation_note(&result),
+            " To keep it short, 47 diagnostics and 9500 bytes of stderr were left out; `get_cargo_result` with `result_id` 3 pages through all of it."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_stages() {
+        let stages: AutoCheckConfig = toml::from_str(indoc::indoc! {r#"
+            [[stages]]
+            command = "clippy"
+            args = ["--all-targets", "--", "-D", "warnings"]
+
+            [[stages]]
+            command = "fmt"
+            args = ["--check"]
+        "#})
+        .unwrap();
+        assert!(check_stages(&stages.stages, None).await.is_ok());
+
+        let stage = |command: &str, args: &[&str]| Stage {
+            command: command.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            ..AutoCheckConfig::default().stages.remove(0)
+        };
+        let error = check_stages(&[stage("clean", &[])], None)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "`cargo clean` cannot run as an automatic check; stages can only run check, clippy, fmt, test, build"
+        );
+        assert!(
+            check_stages(&[stage("check", &["--config", "x"])], None)
+                .await
+                .is_err()
+        );
+        assert!(
+            check_stages(&[stage("test", &["-p", "--config=x"])], None)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::cargo_command::{
+    CargoCommandJsonResult, CargoCommandOptions, CommandStatus, execute_cargo_command,
+};
+use crate::diagnostic::{Diagnostic, DiagnosticLevel};
+use crate::session::SessionState;
+
+/// Command whose diagnostics can serve as a baseline
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BaselineCommand {
+    #[default]
+    Check,
+    Clippy,
+}
+
+impl BaselineCommand {
+    pub fn subcommand(self) -> &'static str {
+        match self {
+            BaselineCommand::Check => "check",
+            BaselineCommand::Clippy => "clippy",
+        }
+    }
+
+    /// The baseline that applies to `cargo <subcommand>`, if any
+    pub fn for_subcommand(subcommand: &str) -> Option<Self> {
+        match subcommand {
+            "check" => Some(BaselineCommand::Check),
+            "clippy" => Some(BaselineCommand::Clippy),
+            _ => None,
+        }
+    }
+}
+
+/// The diagnostics of a run that later runs are compared to
+#[derive(Debug, Clone)]
+pub struct Baseline {
+    /// The arguments cargo ran with; only runs with the same arguments are compared, since
+    /// other packages, targets or features report other diagnostics
+    pub args: Vec<String>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// How the diagnostics of a run compare to the session's baseline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct BaselineComparison {
+    /// Diagnostics the baseline does not have; these are the ones left in `diagnostics`
+    pub new: usize,
+    /// Diagnostics the baseline already had, left out of `diagnostics`
+    pub unchanged: usize,
+    /// Baseline diagnostics that no longer occur
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub fixed: Vec<Diagnostic>,
+}
+
+impl BaselineComparison {
+    /// Compare `result` to `baseline`, removing the diagnostics the baseline already had
+    /// (along with their rendered messages) from the result. Diagnostics are matched by
+    /// code, message and file, so they still match after unrelated edits moved them around.
+    /// Diagnostics without a source location, like `aborting due to 2 previous errors`,
+    /// are left in place and not counted.
+    pub fn apply(baseline: &[Diagnostic], result: &mut CargoCommandJsonResult) -> Self {
+        // Owned keys, as the map outlives the borrows of the result's diagnostics. The level
+        // is left out, since `-D warnings` turns the baseline's warnings into errors.
+        let key = |d: &Diagnostic| {
+            let (_, code, message, file) = d.key();
+            (
+                code.map(str::to_string),
+                message.to_string(),
+                file.map(str::to_string),
+            )
+        };
+        let mut remaining: BTreeMap<_, Vec<&Diagnostic>> = BTreeMap::new();
+        for diagnostic in baseline.iter().filter(|d| !d.spans.is_empty()) {
+            remaining
+                .entry(key(diagnostic))
+                .or_default()
+                .push(diagnostic);
+        }
+
+        let (mut new, mut unchanged) = (0, 0);
+        let mut items = result.take_items();
+        items.retain(|(_, diagnostic)| {
+            let Some(diagnostic) = diagnostic.as_ref().filter(|d| !d.spans.is_empty()) else {
+                return true;
+            };
+            match remaining.get_mut(&key(diagnostic)).and_then(Vec::pop) {
+                Some(_) => {
+                    unchanged += 1;
+                    false
+                }
+                None => {
+                    new += 1;
+                    true
+                }
+            }
+        });
+        result.put_items(items);
+
+        BaselineComparison {
+            new,
+            unchanged,
+            fixed: remaining.into_values().flatten().cloned().collect(),
+        }
+    }
+}
+
+/// Whether `diagnostics`, what [`BaselineComparison::apply`] left of a run, has an error the
+/// `baseline` does not. Unlike `apply`, this counts the errors without a source location,
+/// such as a failed link.
+pub(crate) fn has_new_errors(baseline: &[Diagnostic], diagnostics: &[Diagnostic]) -> bool {
+    diagnostics
+        .iter()
+        .filter(|d| matches!(d.level, DiagnosticLevel::Error | DiagnosticLevel::Ice))
+        .any(|d| !d.spans.is_empty() || !baseline.iter().any(|b| b.key() == d.key()))
+}
+
+/// Run `cargo <command> <args>` and record its diagnostics as the session's baseline
+pub(crate) async fn capture(
+    session: &SessionState,
+    command: BaselineCommand,
+    args: Vec<String>,
+    cwd: Option<String>,
+    options: CargoCommandOptions,
+) -> anyhow::Result<CargoCommandJsonResult> {
+    let cargo_args = args.iter().map(|a| a.as_str()).collect();
+    let result =
+        execute_cargo_command(command.subcommand(), cargo_args, cwd, false, options).await?;
+    if result.status != CommandStatus::Completed {
+        anyhow::bail!(
+            "`{}` did not finish, so no baseline was recorded",
+            result.command
+        );
+    }
+    session.set_baseline(
+        command,
+        Baseline {
+            args,
+            diagnostics: result.diagnostics.clone(),
+        },
+    );
+    Ok(result)
+}
+
+/// Capture the baselines the project configuration asks for when a session starts. Failures
+/// are only logged, since nothing is waiting for the result.
+pub(crate) async fn capture_at_session_start(session: &SessionState) {
+    let cwd = session.cwd().await;
+    let config = match session.config(cwd.as_deref().map(Path::new)) {
+        Ok(config) => config,
+        Err(err) => {
+            tracing::warn!(?err, "not capturing a baseline");
+            return;
+        }
+    };
+    for &command in &config.baseline.at_session_start {
+        let options = CargoCommandOptions {
+            timeout: Some(config.timeout_for("baseline")),
+            toolchain: session.toolchain().await,
+            ..Default::default()
+        };
+        if let Err(err) = capture(session, command, vec![], cwd.clone(), options).await {
+            tracing::warn!(?err, ?command, "failed to capture a baseline");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(message: &str, line: usize) -> Diagnostic {
+        Diagnostic::for_test(DiagnosticLevel::Warning, message)
+            .with_code("dead_code")
+            .at("src/lib.rs", line)
+    }
+
+    #[test]
+    fn test_compare() {
+        let baseline = vec![
+            diagnostic("function `a` is never used", 1),
+            diagnostic("function `b` is never used", 2),
+        ];
+        let diagnostics = vec![
+            // Moved down by an edit, but still the same warning
+            diagnostic("function `a` is never used", 10),
+            diagnostic("function `c` is never used", 3),
+        ];
+        let mut result = CargoCommandJsonResult::for_test("cargo check", diagnostics);
+
+        let comparison = BaselineComparison::apply(&baseline, &mut result);
+        assert_eq!((comparison.new, comparison.unchanged), (1, 1));
+        assert_eq!(comparison.fixed, [baseline[1].clone()]);
+        assert_eq!(result.diagnostics[0].message, "function `c` is never used");
+        assert_eq!(result.messages, ["function `c` is never used"]);
+    }
+
+    #[test]
+    fn test_has_new_errors() {
+        let error = |message: &str| Diagnostic::for_test(DiagnosticLevel::Error, message);
+        let baseline = vec![
+            error("cannot find value `x`").at("src/lib.rs", 1),
+            error("aborting due to 1 previous error"),
+        ];
+        assert!(!has_new_errors(
+            &baseline,
+            &[error("aborting due to 1 previous error")]
+        ));
+        assert!(has_new_errors(
+            &baseline,
+            &[error("linking with `cc` failed")]
+        ));
+        assert!(has_new_errors(
+            &baseline,
+            &[error("cannot find value `y`").at("src/lib.rs", 2)]
+        ));
+        assert!(!has_new_errors(
+            &baseline,
+            &[Diagnostic::for_test(DiagnosticLevel::Warning, "unused").at("src/lib.rs", 3)]
+        ));
+    }
+}
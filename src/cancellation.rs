@@ -0,0 +1,157 @@
+//! Cancelling single tool calls of the plain MCP server with `notifications/cancelled`.
+//!
+//! The MCP server passes each request on to its MCP service under an id of its own, so the
+//! service cannot match the client's `notifications/cancelled` to the call. Instead, [`serve`]
+//! notes the id of each `tools/call` request in its arguments, where [`CallInput`] picks it up,
+//! and cancels the call's token when the client cancels that id.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use sacp::mcp::{McpClientPeer, McpClientToServer, McpServerPeer, McpServerToClient};
+use sacp::{Component, JrConnectionCx, MessageCx};
+use schemars::{JsonSchema, Schema, SchemaGenerator};
+use serde::Deserialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::session::SessionState;
+
+/// Argument that carries the id of the `tools/call` request to the tool
+const REQUEST_ID: &str = "_mcp_request_id";
+
+/// The arguments of a tool call, along with the id of its request if the client can cancel it
+#[derive(Deserialize)]
+pub(crate) struct CallInput<P> {
+    #[serde(rename = "_mcp_request_id", default)]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub input: P,
+}
+
+/// The tool's own schema; the request id is not for the agent to fill in
+impl<P: JsonSchema> JsonSchema for CallInput<P> {
+    fn schema_name() -> Cow<'static, str> {
+        P::schema_name()
+    }
+
+    fn schema_id() -> Cow<'static, str> {
+        P::schema_id()
+    }
+
+    fn inline_schema() -> bool {
+        P::inline_schema()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        P::json_schema(generator)
+    }
+}
+
+/// Tokens of the tool calls that are running, by the id of their request
+#[derive(Default)]
+pub(crate) struct ToolCalls {
+    tokens: Mutex<HashMap<String, CancellationToken>>,
+}
+
+impl ToolCalls {
+    fn start(&self, request_id: &str) {
+        self.tokens
+            .lock()
+            .expect("not poisoned")
+            .insert(request_id.to_string(), CancellationToken::new());
+    }
+
+    fn cancel(&self, request_id: &str) {
+        if let Some(token) = self.tokens.lock().expect("not poisoned").get(request_id) {
+            token.cancel();
+        }
+    }
+
+    fn finish(&self, request_id: &str) {
+        self.tokens.lock().expect("not poisoned").remove(request_id);
+    }
+
+    /// Token that fires when the client cancels request `request_id`
+    pub(crate) fn token(&self, request_id: &str) -> Option<CancellationToken> {
+        self.tokens
+            .lock()
+            .expect("not poisoned")
+            .get(request_id)
+            .cloned()
+    }
+}
+
+/// Serve `server`, whose tools run in `session`, to `client`, keeping track of tool calls so
+/// the client can cancel them
+pub(crate) async fn serve(
+    session: Arc<SessionState>,
+    server: impl Component<McpServerToClient>,
+    client: impl Component<McpClientToServer>,
+) -> Result<(), sacp::Error> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    McpServerToClient::builder()
+        .on_receive_message(
+            async |message: MessageCx, _cx| {
+                tx.send(message)
+                    .map_err(|_| sacp::Error::internal_error().data("the MCP server stopped"))
+            },
+            sacp::on_receive_message!(),
+        )
+        .with_spawned(async move |server_to_client_cx| {
+            McpClientToServer::builder()
+                .on_receive_message(
+                    async |message: MessageCx, _cx| {
+                        server_to_client_cx.send_proxied_message_to(McpClientPeer, message)
+                    },
+                    sacp::on_receive_message!(),
+                )
+                .connect_to(server)?
+                .run_until(async |client_to_server_cx| {
+                    while let Some(message) = rx.recv().await {
+                        forward(&session, &client_to_server_cx, message)?;
+                    }
+                    Ok(())
+                })
+                .await
+        })
+        .serve(client)
+        .await
+}
+
+/// Pass `message` from the client on to the server
+fn forward(
+    session: &Arc<SessionState>,
+    cx: &JrConnectionCx<McpClientToServer>,
+    message: MessageCx,
+) -> Result<(), sacp::Error> {
+    match message {
+        MessageCx::Request(mut request, request_cx) if request.method == "tools/call" => {
+            let request_id = request_cx.id().to_string();
+            if let Some(params) = request.params.as_object_mut() {
+                let arguments = params
+                    .entry("arguments")
+                    .or_insert_with(|| serde_json::json!({}));
+                if let Some(arguments) = arguments.as_object_mut() {
+                    arguments.insert(REQUEST_ID.to_string(), request_id.clone().into());
+                }
+            }
+            session.tool_calls().start(&request_id);
+            let session = session.clone();
+            cx.send_request_to(McpServerPeer, request)
+                .on_receiving_result(async move |result| {
+                    session.tool_calls().finish(&request_id);
+                    request_cx.respond_with_result(result)
+                })
+        }
+        MessageCx::Notification(notification)
+            if notification.method == "notifications/cancelled" =>
+        {
+            if let Some(request_id) = notification.params.get("requestId") {
+                session.tool_calls().cancel(&request_id.to_string());
+            }
+            cx.send_notification_to(McpServerPeer, notification)
+        }
+        message => cx.send_proxied_message_to(McpServerPeer, message),
+    }
+}
@@ -1,100 +1,814 @@
+use crate::baseline::BaselineComparison;
+use crate::diagnostic::{Diagnostic, DiagnosticLevel};
+use crate::toolchain;
 use anyhow::Result;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
 use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+/// What we keep from the stdout of a cargo command run with `--message-format json`
+#[derive(Default)]
+struct FilteredStdout {
+    messages: Vec<serde_json::Value>,
+    diagnostics: Vec<Diagnostic>,
+    /// Lines that are not cargo JSON messages, e.g. test harness output or the output of
+    /// `cargo run`
+    other: String,
+    build_success: bool,
+}
 
 /// Filter cargo JSON messages to keep only compiler-message and build-finished
-fn filter_json_messages(stdout: &str) -> (Vec<serde_json::Value>, bool) {
-    let mut messages = Vec::new();
-    let mut build_success = true;
-
-    for line in stdout.lines() {
-        if let Ok(json) = serde_json::from_str::<serde_json::Value>(line) {
-            if let Some(reason) = json.get("reason").and_then(|r| r.as_str()) {
-                if reason == "compiler-message" {
-                    json.get("message")
-                        .and_then(|m| m.get("rendered"))
-                        .map(|s| messages.push(s.clone()));
-                } else if reason == "build-finished" {
-                    build_success = json
-                        .get("success")
-                        .and_then(|s| s.as_bool())
-                        .unwrap_or(true);
+fn filter_json_messages(stdout: &str) -> FilteredStdout {
+    let mut filtered = FilteredStdout {
+        build_success: true,
+        ..Default::default()
+    };
+
+    let mut lines = stdout.lines();
+    for line in lines.by_ref() {
+        let Ok(json) = serde_json::from_str::<serde_json::Value>(line) else {
+            filtered.other.push_str(line);
+            filtered.other.push('\n');
+            continue;
+        };
+        match json.get("reason").and_then(|r| r.as_str()) {
+            Some("compiler-message") => {
+                let Some(message) = json.get("message") else {
+                    continue;
+                };
+                if let Some(rendered) = message.get("rendered") {
+                    filtered.messages.push(rendered.clone());
                 }
+                filtered
+                    .diagnostics
+                    .extend(Diagnostic::from_compiler_message(message));
             }
+            Some("build-finished") => {
+                filtered.build_success = json
+                    .get("success")
+                    .and_then(|s| s.as_bool())
+                    .unwrap_or(true);
+                break;
+            }
+            _ => {}
         }
     }
+    // Whatever comes after the build is the output of the programs cargo runs, which may
+    // well be JSON itself, such as the events of `cargo test -- --format json`
+    for line in lines {
+        filtered.other.push_str(line);
+        filtered.other.push('\n');
+    }
 
-    (messages, build_success)
+    filtered
 }
 
-/// Filter out cargo file lock messages from stderr
+/// Filter out cargo file lock messages and progress bars from stderr
 fn filter_stderr(stderr: &str) -> String {
     stderr
         .lines()
-        .filter(|line| !line.contains("Blocking waiting for file lock"))
+        // Progress bars are redrawn after `\r`; keep what a terminal would end up showing
+        .map(|line| {
+            line.rsplit('\r')
+                .find(|segment| !segment.trim().is_empty())
+                .unwrap_or("")
+        })
+        .filter(|line| !is_progress_bar(line) && !line.contains("Blocking waiting for file lock"))
         .collect::<Vec<_>>()
         .join("\n")
 }
 
+/// Whether a line of stderr is one of cargo's progress bars, e.g. `Building [==>  ] 3/7: serde`
+fn is_progress_bar(line: &str) -> bool {
+    line.trim().split_once(' ').is_some_and(|(word, rest)| {
+        word.chars().all(|c| c.is_ascii_alphabetic()) && rest.starts_with('[')
+    })
+}
+
+/// Parse cargo's build progress bar into units finished, total units and units in flight
+fn parse_build_progress(line: &str) -> Option<(usize, usize, Vec<String>)> {
+    let (_, rest) = line.trim().strip_prefix("Building [")?.split_once("] ")?;
+    let (counts, units) = rest.split_once(": ").unwrap_or((rest, ""));
+    let (finished, total) = counts.split_once('/')?;
+    let units = units
+        .split(", ")
+        .map(str::trim)
+        .filter(|unit| !unit.is_empty())
+        .map(str::to_string)
+        .collect();
+    Some((finished.parse().ok()?, total.parse().ok()?, units))
+}
+
+/// How a cargo command ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandStatus {
+    /// Cargo exited on its own; see `exit_code`
+    Completed,
+    /// Cargo was killed because it ran longer than its timeout
+    TimedOut,
+    /// Cargo was killed because the user cancelled the turn
+    Cancelled,
+}
+
 /// Result of cargo command execution with JSON messages
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct CargoCommandJsonResult {
+    pub status: CommandStatus,
     pub exit_code: Option<i32>,
+    /// Signal that terminated the process, e.g. `SIGSEGV`; `cargo run` reports the
+    /// program's own signal since cargo hands its process over to the program
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
     pub messages: Vec<serde_json::Value>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Standard output that is not part of cargo's JSON messages
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub stdout: String,
     pub stderr: String,
     pub command: String,
     pub build_success: bool,
+    /// Version of the toolchain selected with `+toolchain`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rustc_version: Option<String>,
+    /// Id to fetch the complete result with `get_cargo_result`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_id: Option<u64>,
+    /// Set when the session has a baseline for the command: `diagnostics` then only lists
+    /// what is new relative to it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<BaselineComparison>,
+    /// Short explanations of the error codes among `diagnostics`, when asked for with `explain`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub explanations: BTreeMap<String, String>,
+    /// Set when parts of the result were left out to fit the output budget
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub truncated: Option<Truncation>,
+}
+
+/// Output size tool results are trimmed to when no budget is configured, about 10k tokens
+pub const DEFAULT_OUTPUT_BYTES: usize = 40_000;
+
+/// Warnings kept per lint once a result is over budget; the rest are only counted
+const WARNINGS_PER_LINT: usize = 3;
+
+/// Room left in the budget for the `truncated` report itself
+const TRUNCATION_REPORT_BYTES: usize = 512;
+
+/// How much output a tool may return to the agent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct OutputBudget {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<usize>,
+    /// Estimated at four bytes per token
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<usize>,
 }
 
+impl OutputBudget {
+    /// The budget in bytes: the smaller limit if both are set, the default if neither is
+    pub fn limit(&self) -> usize {
+        let from_tokens = self.max_tokens.map(|tokens| tokens.saturating_mul(4));
+        match (self.max_bytes, from_tokens) {
+            (Some(bytes), Some(tokens)) => bytes.min(tokens),
+            (Some(limit), None) | (None, Some(limit)) => limit,
+            (None, None) => DEFAULT_OUTPUT_BYTES,
+        }
+    }
+}
+
+/// What was left out of a result to fit the output budget
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Truncation {
+    /// Warnings left out per lint, beyond the first few of each
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub collapsed_lints: BTreeMap<String, usize>,
+    /// Further diagnostics left out from the end of the list, which has errors first
+    pub elided_diagnostics: usize,
+    pub elided_stdout_bytes: usize,
+    pub elided_stderr_bytes: usize,
+    /// Entries left out of the tool's own lists, such as `tests`, dependency tree nodes or
+    /// the workspace `members`
+    pub elided_entries: usize,
+}
+
+/// A tool's response, built around the result of the cargo command it ran, which
+/// [`fit_to_budget`] trims as a whole
+pub trait ToolResponse: Serialize {
+    fn command_mut(&mut self) -> &mut CargoCommandJsonResult;
+
+    /// Shrink what the response adds to the command's result to about `limit` bytes, noting
+    /// what was left out in `truncation`
+    fn trim_parts(&mut self, _limit: usize, _truncation: &mut Truncation) {}
+}
+
+impl ToolResponse for CargoCommandJsonResult {
+    fn command_mut(&mut self) -> &mut CargoCommandJsonResult {
+        self
+    }
+}
+
+/// Shrink `response` to fit `budget`. What the tool adds to the command's result is trimmed
+/// first, leaving the command's result at least half of the budget, and the command's result
+/// then gets what is left; see [`CargoCommandJsonResult::fit_within`].
+pub fn fit_to_budget<T: ToolResponse>(response: &mut T, budget: &OutputBudget) {
+    let limit = budget.limit();
+    let mut truncation = Truncation::default();
+    let parts_len =
+        |response: &mut T| json_len(&*response).saturating_sub(json_len(&*response.command_mut()));
+    if json_len(&*response) > limit {
+        let command_len = json_len(&*response.command_mut()).min(limit / 2);
+        let parts_limit = limit.saturating_sub(command_len + TRUNCATION_REPORT_BYTES);
+        if parts_len(response) > parts_limit {
+            response.trim_parts(parts_limit, &mut truncation);
+        }
+    }
+    let parts_len = parts_len(response);
+    response
+        .command_mut()
+        .fit_within(limit.saturating_sub(parts_len), truncation);
+}
+
+/// Length of `value` as JSON
+pub(crate) fn json_len(value: &impl Serialize) -> usize {
+    serde_json::to_string(value).map_or(0, |s| s.len())
+}
+
+/// Keep as many entries from the front of `entries` as fit in `limit` bytes of JSON, and
+/// return how many were left out
+pub(crate) fn keep_within<T: Serialize>(entries: &mut Vec<T>, limit: usize) -> usize {
+    let mut used = 0;
+    let kept = entries
+        .iter()
+        .take_while(|entry| {
+            used += json_len(entry) + 1;
+            used <= limit
+        })
+        .count();
+    let elided = entries.len() - kept;
+    entries.truncate(kept);
+    elided
+}
+
+/// A rendered message together with the diagnostic it renders, when the two can be paired
+pub(crate) type Item = (Option<serde_json::Value>, Option<Diagnostic>);
+
+impl CargoCommandJsonResult {
+    /// Shrink the result to `limit` bytes, adding to what `truncation` says was already left
+    /// out of the response. Identical diagnostics are always merged; a result that is still
+    /// too large gets errors sorted before warnings, warnings collapsed to a few per lint,
+    /// stdout and stderr cut in the middle, and finally diagnostics dropped from the end.
+    /// Explanations are only kept for the codes of the diagnostics that are left. What was
+    /// left out can be fetched with `get_cargo_result`.
+    pub fn fit_within(&mut self, limit: usize, mut truncation: Truncation) {
+        let mut items = Vec::new();
+        for item in self.take_items() {
+            if !items.contains(&item) {
+                items.push(item);
+            }
+        }
+        self.put_items(items);
+        if json_len(self) > limit {
+            self.trim(limit, &mut truncation);
+        }
+        let codes: Vec<String> = self
+            .diagnostics
+            .iter()
+            .filter_map(|d| d.code.clone())
+            .collect();
+        self.explanations.retain(|code, _| codes.contains(code));
+        if truncation != Truncation::default() {
+            self.truncated = Some(truncation);
+        }
+    }
+
+    fn trim(&mut self, limit: usize, truncation: &mut Truncation) {
+        let mut items = self.take_items();
+        items.sort_by_key(|item| level_rank(item_level(item)));
+
+        let mut per_lint: BTreeMap<String, usize> = BTreeMap::new();
+        items.retain(|item| {
+            let lint = match item {
+                (_, Some(d)) if d.level == DiagnosticLevel::Warning => d.code.clone(),
+                _ => None,
+            };
+            let Some(lint) = lint else {
+                return true;
+            };
+            let seen = per_lint.entry(lint.clone()).or_default();
+            *seen += 1;
+            if *seen <= WARNINGS_PER_LINT {
+                return true;
+            }
+            *truncation.collapsed_lints.entry(lint).or_default() += 1;
+            false
+        });
+
+        let item_len = |(message, diagnostic): &Item| {
+            let len = |v: Option<String>| v.map_or(0, |s| s.len() + 1);
+            len(message.as_ref().map(|m| m.to_string()))
+                + len(diagnostic
+                    .as_ref()
+                    .and_then(|d| serde_json::to_string(d).ok()))
+        };
+        let items_len: usize = items.iter().map(item_len).sum();
+        let base_len = json_len(self) + TRUNCATION_REPORT_BYTES;
+        if base_len + items_len > limit {
+            // Output text gets at most a quarter of the budget each, so diagnostics keep
+            // at least half of it
+            truncation.elided_stdout_bytes += truncate_middle(&mut self.stdout, limit / 4);
+            truncation.elided_stderr_bytes += truncate_middle(&mut self.stderr, limit / 4);
+        }
+
+        let base_len = json_len(self) + TRUNCATION_REPORT_BYTES;
+        let mut used = base_len;
+        let mut kept = 0;
+        for item in &items {
+            used += item_len(item);
+            // Keep at least one item, so a single huge error still comes through
+            if used > limit && kept > 0 {
+                break;
+            }
+            kept += 1;
+        }
+        truncation.elided_diagnostics = items.len() - kept;
+        items.truncate(kept);
+        self.put_items(items);
+    }
+
+    /// Take the messages and diagnostics out of the result, paired up where possible
+    pub(crate) fn take_items(&mut self) -> Vec<Item> {
+        let messages = std::mem::take(&mut self.messages);
+        let diagnostics = std::mem::take(&mut self.diagnostics);
+        if messages.len() == diagnostics.len() {
+            messages
+                .into_iter()
+                .map(Some)
+                .zip(diagnostics.into_iter().map(Some))
+                .collect()
+        } else {
+            // Without a one-to-one match, there is no telling which message renders which
+            // diagnostic
+            messages
+                .into_iter()
+                .map(|m| (Some(m), None))
+                .chain(diagnostics.into_iter().map(|d| (None, Some(d))))
+                .collect()
+        }
+    }
+
+    pub(crate) fn put_items(&mut self, items: Vec<Item>) {
+        for (message, diagnostic) in items {
+            self.messages.extend(message);
+            self.diagnostics.extend(diagnostic);
+        }
+    }
+}
+
+/// The level of a diagnostic, or of a rendered message going by how it starts
+fn item_level(item: &Item) -> DiagnosticLevel {
+    match item {
+        (_, Some(diagnostic)) => diagnostic.level,
+        (Some(message), None) => match message.as_str() {
+            Some(m) if m.starts_with("error") => DiagnosticLevel::Error,
+            Some(m) if m.starts_with("warning") => DiagnosticLevel::Warning,
+            _ => DiagnosticLevel::Note,
+        },
+        (None, None) => DiagnosticLevel::Unknown,
+    }
+}
+
+/// Errors first, then warnings, then everything else
+fn level_rank(level: DiagnosticLevel) -> u8 {
+    match level {
+        DiagnosticLevel::Ice | DiagnosticLevel::Error => 0,
+        DiagnosticLevel::Warning => 1,
+        _ => 2,
+    }
+}
+
+/// Cut `text` down to about `max` bytes by removing its middle, which keeps both how the
+/// output started and how it ended. Returns the number of bytes removed.
+pub(crate) fn truncate_middle(text: &mut String, max: usize) -> usize {
+    if text.len() <= max {
+        return 0;
+    }
+    let head = floor_char_boundary(text, max / 2);
+    let tail = ceil_char_boundary(text, text.len() - max / 2);
+    let elided = tail - head;
+    *text = format!(
+        "{}\n[... {elided} bytes elided ...]\n{}",
+        &text[..head],
+        &text[tail..]
+    );
+    elided
+}
+
+/// The closest index at or before `index` where a character of `text` starts
+pub(crate) fn floor_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// The closest index at or after `index` where a character of `text` starts
+fn ceil_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index += 1;
+    }
+    index
+}
+
+/// How far a running cargo command has got
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    /// Compilation units (crates, build scripts, test targets) that are done
+    pub compiled: usize,
+    /// Total number of units, once cargo has reported it
+    pub total: Option<usize>,
+    /// Units being compiled right now
+    pub current: Vec<String>,
+    pub elapsed: Duration,
+    /// Set on the last report, after cargo exited: whether the command succeeded
+    pub finished: Option<bool>,
+}
+
+impl Progress {
+    /// One line for the user, e.g. `3/7 units compiled, building serde, tokio (12s)`
+    pub fn summary(&self) -> String {
+        let mut summary = match self.total {
+            Some(total) => format!("{}/{total} units compiled", self.compiled),
+            None => format!("{} units compiled", self.compiled),
+        };
+        if !self.current.is_empty() && self.finished.is_none() {
+            summary.push_str(", building ");
+            summary.push_str(&self.current.join(", "));
+        }
+        summary.push_str(&format!(" ({}s)", self.elapsed.as_secs()));
+        summary
+    }
+}
+
+/// Receives [`Progress`] reports while a cargo command runs
+pub type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
+
+/// Limits on how long a cargo command may run, and where to report its progress
+#[derive(Clone, Default)]
+pub struct CargoCommandOptions {
+    pub timeout: Option<Duration>,
+    pub cancellation: Option<CancellationToken>,
+    /// Called whenever cargo finishes or starts a unit, and once more when it exits
+    pub progress: Option<ProgressCallback>,
+    /// Rustup toolchain to run cargo with, as in `cargo +nightly`
+    pub toolchain: Option<String>,
+    /// Written to cargo's standard input, which `cargo run` passes on to the program;
+    /// without it standard input is empty
+    pub stdin: Option<String>,
+}
+
+/// Builds up [`Progress`] from cargo's JSON messages and its progress bar on stderr
+struct ProgressTracker {
+    start: Instant,
+    callback: Option<ProgressCallback>,
+    state: Mutex<ProgressState>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    artifacts: usize,
+    progress: Progress,
+}
+
+impl ProgressTracker {
+    fn update(&self, f: impl FnOnce(&mut ProgressState)) {
+        let Some(callback) = &self.callback else {
+            return;
+        };
+        let mut state = self.state.lock().expect("not poisoned");
+        f(&mut state);
+        state.progress.elapsed = self.start.elapsed();
+        callback(&state.progress);
+    }
+
+    fn stdout_line(&self, line: &[u8]) {
+        if self.callback.is_none() {
+            return;
+        }
+        let Ok(json) = serde_json::from_slice::<serde_json::Value>(line) else {
+            return;
+        };
+        if json.get("reason").and_then(|r| r.as_str()) == Some("compiler-artifact") {
+            self.update(|state| {
+                state.artifacts += 1;
+                state.progress.compiled = state.progress.compiled.max(state.artifacts);
+            });
+        }
+    }
+
+    fn stderr_line(&self, line: &[u8]) {
+        if self.callback.is_none() {
+            return;
+        }
+        if let Some((compiled, total, current)) =
+            parse_build_progress(&String::from_utf8_lossy(line))
+        {
+            self.update(|state| {
+                state.progress.compiled = compiled.max(state.artifacts);
+                state.progress.total = Some(total);
+                state.progress.current = current;
+            });
+        }
+    }
+}
+
+/// Kills the process group of a cargo command unless disarmed, so that programs cargo
+/// started (test binaries, `cargo run` targets) do not outlive a killed or dropped command
+struct ProcessGroupGuard {
+    pgid: Option<u32>,
+}
+
+impl ProcessGroupGuard {
+    fn kill(&mut self) {
+        #[cfg(unix)]
+        if let Some(pgid) = self.pgid.take() {
+            // SAFETY: `killpg` has no memory safety preconditions
+            unsafe {
+                libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+            }
+        }
+    }
+
+    fn disarm(&mut self) {
+        self.pgid = None;
+    }
+}
+
+impl Drop for ProcessGroupGuard {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Read a pipe to the end into `buf`, passing each line (terminated by `\n` or `\r`) to
+/// `on_line` as soon as it arrives. `buf` keeps what was read if this is dropped early.
+async fn read_pipe(
+    pipe: Option<impl AsyncRead + Unpin>,
+    buf: &mut Vec<u8>,
+    on_line: impl Fn(&[u8]),
+) {
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    let mut line_start = 0;
+    // A read error just truncates the output; the exit status still tells what happened
+    while let Ok(1..) = pipe.read_buf(buf).await {
+        while let Some(len) = buf[line_start..]
+            .iter()
+            .position(|b| matches!(b, b'\n' | b'\r'))
+        {
+            on_line(&buf[line_start..line_start + len]);
+            line_start += len + 1;
+        }
+    }
+    if line_start < buf.len() {
+        on_line(&buf[line_start..]);
+    }
+}
+
+/// Name of a signal, e.g. `SIGSEGV` for 11
+#[cfg(unix)]
+fn signal_name(signal: i32) -> String {
+    let name = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        _ => return format!("signal {signal}"),
+    };
+    name.to_string()
+}
+
+/// How long cargo's output pipes may stay open after it exited
+const PIPE_DRAIN_TIME: Duration = Duration::from_millis(500);
+
 /// Execute cargo command with JSON message format
 pub async fn execute_cargo_command(
     command: &str,
     args: Vec<&str>,
     cwd: Option<String>,
     skip_json_format: bool,
+    options: CargoCommandOptions,
 ) -> Result<CargoCommandJsonResult> {
     let mut cmd = Command::new("cargo");
-    cmd.arg(&command);
-    cmd.args(&args);
+    if let Some(toolchain) = &options.toolchain {
+        cmd.arg(format!("+{toolchain}"));
+    }
+    cmd.arg(command);
 
+    // Goes before `args` so it still reaches cargo when they contain `--`
     if !skip_json_format {
         cmd.args(["--message-format", "json"]);
     }
+    cmd.args(&args);
 
     if let Some(cwd) = &cwd {
         cmd.current_dir(cwd);
     }
 
-    let output = cmd.output().await?;
+    if options.progress.is_some() {
+        // Cargo only draws its progress bar, which has the unit counts, for terminals by default
+        cmd.env("CARGO_TERM_PROGRESS_WHEN", "always")
+            .env("CARGO_TERM_PROGRESS_WIDTH", "200");
+    }
 
-    let (messages, build_success) = filter_json_messages(&String::from_utf8_lossy(&output.stdout));
+    let stdin = if options.stdin.is_some() {
+        Stdio::piped()
+    } else {
+        Stdio::null()
+    };
+    cmd.stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    #[cfg(unix)]
+    cmd.process_group(0);
 
-    Ok(CargoCommandJsonResult {
-        exit_code: output.status.code(),
-        messages,
-        stderr: filter_stderr(&String::from_utf8_lossy(&output.stderr)),
-        command: format!(
-            "cargo {} {}{}",
-            command,
-            args.join(" "),
-            if skip_json_format {
-                ""
-            } else {
-                " --message-format json"
+    let mut child = cmd.spawn()?;
+    let mut group = ProcessGroupGuard { pgid: child.id() };
+    let stdout_pipe = child.stdout.take();
+    let stderr_pipe = child.stderr.take();
+    let stdin_pipe = child.stdin.take();
+    let write_stdin = async {
+        if let (Some(mut pipe), Some(input)) = (stdin_pipe, &options.stdin) {
+            // The program may exit without reading everything; that is not our error
+            let _ = pipe.write_all(input.as_bytes()).await;
+        }
+    };
+
+    // Set once cargo has exited, however that came about
+    let exited = CancellationToken::new();
+    let wait = async {
+        let _exited = exited.clone().drop_guard();
+        let timeout = async {
+            match options.timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+        let cancelled = async {
+            match &options.cancellation {
+                Some(token) => token.cancelled().await,
+                None => std::future::pending().await,
             }
-        ),
-        build_success,
+        };
+        let status = tokio::select! {
+            biased;
+            () = cancelled => CommandStatus::Cancelled,
+            () = timeout => CommandStatus::TimedOut,
+            exit_status = child.wait() => {
+                return exit_status.map(|s| (CommandStatus::Completed, Some(s)));
+            }
+        };
+        // Killing the group also closes the pipes held by cargo's children
+        group.kill();
+        child.kill().await?;
+        Ok((status, None))
+    };
+    let tracker = ProgressTracker {
+        start: Instant::now(),
+        callback: options.progress.clone(),
+        state: Default::default(),
+    };
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let io = async {
+        tokio::join!(
+            read_pipe(stdout_pipe, &mut stdout, |line| tracker.stdout_line(line)),
+            read_pipe(stderr_pipe, &mut stderr, |line| tracker.stderr_line(line)),
+            write_stdin,
+        );
+    };
+    // A process that inherited the pipes, like a server a test started in the background, can
+    // keep them open long after cargo exited, so they only get a moment to drain after that
+    let drained = async {
+        tokio::select! {
+            () = io => true,
+            () = async {
+                exited.cancelled().await;
+                tokio::time::sleep(PIPE_DRAIN_TIME).await;
+            } => false,
+        }
+    };
+    let (waited, drained) = tokio::join!(wait, drained);
+    let (status, exit_status) = waited?;
+    if drained {
+        group.disarm();
+    } else {
+        // Nothing reads the pipes any more, so don't leave their holders behind
+        group.kill();
+    }
+    let exit_code = exit_status.and_then(|s| s.code());
+    #[cfg(unix)]
+    let signal = exit_status
+        .and_then(|s| std::os::unix::process::ExitStatusExt::signal(&s))
+        .map(signal_name);
+    #[cfg(not(unix))]
+    let signal = None;
+    tracker.update(|state| {
+        state.progress.finished = Some(status == CommandStatus::Completed && exit_code == Some(0));
+    });
+
+    let stdout = String::from_utf8_lossy(&stdout);
+    let filtered = if skip_json_format {
+        FilteredStdout {
+            other: stdout.into_owned(),
+            build_success: true,
+            ..Default::default()
+        }
+    } else {
+        filter_json_messages(&stdout)
+    };
+
+    let rustc_version = match &options.toolchain {
+        Some(toolchain) => toolchain::rustc_version(Some(toolchain), cwd.as_deref()).await,
+        None => None,
+    };
+
+    Ok(CargoCommandJsonResult {
+        status,
+        exit_code,
+        signal,
+        messages: filtered.messages,
+        diagnostics: filtered.diagnostics,
+        stdout: filtered.other,
+        stderr: filter_stderr(&String::from_utf8_lossy(&stderr)),
+        command: std::iter::once("cargo")
+            .chain(cmd.as_std().get_args().filter_map(|a| a.to_str()))
+            .collect::<Vec<_>>()
+            .join(" "),
+        build_success: filtered.build_success && status == CommandStatus::Completed,
+        rustc_version,
+        result_id: None,
+        baseline: None,
+        explanations: BTreeMap::new(),
+        truncated: None,
     })
 }
 
+#[cfg(test)]
+impl CargoCommandJsonResult {
+    /// A successful result of `command` for tests, with each of `diagnostics` rendered as its
+    /// message
+    pub(crate) fn for_test(command: &str, diagnostics: Vec<Diagnostic>) -> Self {
+        CargoCommandJsonResult {
+            status: CommandStatus::Completed,
+            exit_code: Some(0),
+            signal: None,
+            messages: diagnostics
+                .iter()
+                .map(|d| serde_json::Value::from(d.message.clone()))
+                .collect(),
+            diagnostics,
+            stdout: String::new(),
+            stderr: String::new(),
+            command: command.to_string(),
+            build_success: true,
+            rustc_version: None,
+            result_id: None,
+            baseline: None,
+            explanations: BTreeMap::new(),
+            truncated: None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::libtest::CargoTestResult;
 
     #[tokio::test]
     async fn test_cargo_version() {
-        let result = execute_cargo_command("version", vec![], None, true)
+        let result = execute_cargo_command("version", vec![], None, true, Default::default())
             .await
             .unwrap();
         assert_eq!(result.exit_code, Some(0));
@@ -102,10 +816,221 @@ mod tests {
 
     #[tokio::test]
     async fn test_cargo_with_args() {
-        let result = execute_cargo_command("help", vec!["build"], None, true)
+        let result = execute_cargo_command("help", vec!["build"], None, true, Default::default())
             .await
             .unwrap();
         assert_eq!(result.exit_code, Some(0));
         assert!(result.command.contains("cargo help build"));
     }
+
+    #[tokio::test]
+    async fn test_cancelled() {
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let result = execute_cargo_command(
+            "version",
+            vec![],
+            None,
+            true,
+            CargoCommandOptions {
+                cancellation: Some(cancellation),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.status, CommandStatus::Cancelled);
+        assert_eq!(result.exit_code, None);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out() {
+        let result = execute_cargo_command(
+            "version",
+            vec![],
+            None,
+            true,
+            CargoCommandOptions {
+                timeout: Some(Duration::ZERO),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.status, CommandStatus::TimedOut);
+        assert!(!result.build_success);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_background_process_keeps_pipes() {
+        let dir = std::env::temp_dir().join(format!("background-pipes-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        std::fs::write(
+            dir.join("Cargo.toml"),
+            "[package]\nname = \"background\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        // The `sleep` inherits stdout and stderr and outlives the program
+        std::fs::write(
+            dir.join("src/main.rs"),
+            r#"fn main() {
+                std::process::Command::new("sleep").arg("60").spawn().unwrap();
+                println!("started");
+            }"#,
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let result = execute_cargo_command(
+            "run",
+            vec!["--quiet"],
+            Some(dir.display().to_string()),
+            true,
+            CargoCommandOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30));
+        assert_eq!(result.status, CommandStatus::Completed);
+        assert_eq!(result.exit_code, Some(0));
+        assert_eq!(result.stdout.trim(), "started");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_progress_bar() {
+        let stderr = "   Compiling serde v1.0.0\n    Building [===>   ] 1/7: serde_core(build), serde   \r    Building [=====> ] 6/7: app(bin)   \r    Finished `dev` profile\n";
+        assert_eq!(
+            filter_stderr(stderr),
+            "   Compiling serde v1.0.0\n    Finished `dev` profile"
+        );
+        assert_eq!(
+            parse_build_progress("    Building [===>   ] 1/7: serde_core(build), serde   "),
+            Some((
+                1,
+                7,
+                vec!["serde_core(build)".to_string(), "serde".to_string()]
+            ))
+        );
+        assert_eq!(parse_build_progress("   Compiling serde v1.0.0"), None);
+    }
+
+    #[tokio::test]
+    async fn test_progress_reports_exit() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let result = execute_cargo_command(
+            "version",
+            vec![],
+            None,
+            true,
+            CargoCommandOptions {
+                progress: Some(Arc::new({
+                    let reports = reports.clone();
+                    move |progress: &Progress| reports.lock().unwrap().push(progress.clone())
+                })),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.exit_code, Some(0));
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.last().unwrap().finished, Some(true));
+    }
+
+    #[test]
+    fn test_program_output_after_build() {
+        let stdout = "{\"reason\":\"compiler-artifact\"}\n{\"reason\":\"build-finished\",\"success\":true}\nhello\n{\"reason\":\"mine\"}\n";
+        let filtered = filter_json_messages(stdout);
+        assert!(filtered.build_success);
+        assert_eq!(filtered.other, "hello\n{\"reason\":\"mine\"}\n");
+    }
+
+    #[test]
+    fn test_libtest_json_after_build() {
+        let stdout = indoc::indoc! {r#"
+            {"reason":"compiler-artifact"}
+            {"reason":"build-finished","success":true}
+            { "type": "suite", "event": "started", "test_count": 2 }
+            { "type": "test", "event": "started", "name": "tests::a" }
+            { "type": "test", "name": "tests::a", "event": "ok" }
+            { "type": "test", "name": "tests::b", "event": "failed", "stdout": "boom\n" }
+            { "type": "suite", "event": "failed", "passed": 1, "failed": 1 }
+        "#};
+        let command = CargoCommandJsonResult {
+            stdout: filter_json_messages(stdout).other,
+            ..CargoCommandJsonResult::for_test("cargo test", vec![])
+        };
+        let result = CargoTestResult::from(command);
+        assert_eq!((result.summary.passed, result.summary.failed), (1, 1));
+        assert_eq!(result.tests[1].name, "tests::b");
+        assert_eq!(result.tests[1].output.as_deref(), Some("boom\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_signal_name() {
+        assert_eq!(signal_name(libc::SIGSEGV), "SIGSEGV");
+        assert_eq!(signal_name(64), "signal 64");
+    }
+
+    #[test]
+    fn test_truncate_middle() {
+        // Cuts fall between characters, not inside them
+        let mut text = "é".repeat(10);
+        assert_eq!(truncate_middle(&mut text, 7), 16);
+        assert_eq!(text, "é\n[... 16 bytes elided ...]\né");
+
+        let mut text = "short".to_string();
+        assert_eq!(truncate_middle(&mut text, 7), 0);
+        assert_eq!(text, "short");
+    }
+
+    #[test]
+    fn test_fit_to_budget() {
+        let diagnostic = |level: DiagnosticLevel, code: &str, message: &str| {
+            Diagnostic::for_test(level, message).with_code(code)
+        };
+        let mut diagnostics: Vec<_> = (0..50)
+            .map(|i| {
+                diagnostic(
+                    DiagnosticLevel::Warning,
+                    "dead_code",
+                    &format!("unused {i}"),
+                )
+            })
+            .collect();
+        diagnostics.push(diagnostic(DiagnosticLevel::Error, "E0425", "not found"));
+        diagnostics.push(diagnostic(DiagnosticLevel::Error, "E0425", "not found"));
+        let mut result = CargoCommandJsonResult {
+            exit_code: Some(101),
+            stderr: "x".repeat(10_000),
+            build_success: false,
+            // E0599 is not among the diagnostics, say because a baseline hid it
+            explanations: [("E0425", "not found"), ("E0599", "no method")]
+                .into_iter()
+                .map(|(code, text)| (code.to_string(), text.to_string()))
+                .collect(),
+            ..CargoCommandJsonResult::for_test("cargo check", diagnostics)
+        };
+        let full = result.clone();
+
+        fit_to_budget(
+            &mut result,
+            &OutputBudget {
+                max_bytes: Some(2_000),
+                max_tokens: None,
+            },
+        );
+        assert!(serde_json::to_string(&result).unwrap().len() <= 2_000);
+        assert_eq!(result.diagnostics.len(), result.messages.len());
+        assert_eq!(result.diagnostics[0].level, DiagnosticLevel::Error);
+        assert_eq!(result.diagnostics[1].level, DiagnosticLevel::Warning);
+        let truncation = result.truncated.unwrap();
+        assert_eq!(truncation.collapsed_lints["dead_code"], 47);
+        assert_eq!(truncation.elided_stderr_bytes, 9_500);
+        assert_eq!(full.diagnostics.len(), 52);
+        assert_eq!(result.explanations.keys().collect::<Vec<_>>(), ["E0425"]);
+    }
 }
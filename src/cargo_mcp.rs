@@ -1,32 +1,152 @@
-use crate::cargo_command::execute_cargo_command;
+use crate::baseline::{self, BaselineCommand, BaselineComparison};
+use crate::cancellation::CallInput;
+use crate::cargo_command::{
+    CargoCommandJsonResult, CargoCommandOptions, OutputBudget, ProgressCallback, ToolResponse,
+    execute_cargo_command, fit_to_budget,
+};
+use crate::config::{Config, ToolPolicy};
+use crate::explain;
+use crate::extra_args;
+use crate::fmt::CargoFmtResult;
+use crate::libtest::{self, CargoTestResult};
+use crate::metadata::{self, CargoMetadataResult};
+use crate::permission::{self, Decision};
+use crate::progress;
+use crate::results::{ResultQuery, StoredResult};
+use crate::run::CargoRunResult;
+use crate::selection::BuildSelection;
+use crate::session::SessionState;
+use crate::suggestions::{self, SuggestionFilter};
+use crate::toolchain;
+use crate::tree::{CargoTreeResult, TreeQuery};
+use anyhow::Context;
+use sacp::mcp::McpServerToClient;
 use sacp::{
-    ProxyToConductor,
-    mcp_server::{McpContext, McpServer},
+    BoxFuture, JrConnectionCx, JrLink, ProxyToConductor,
+    mcp_server::{McpContext, McpServer, McpTool},
 };
 use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::convert::identity;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
 
+/// Where and with what a tool runs cargo; part of every cargo tool's inputs
 #[derive(Serialize, Deserialize, JsonSchema)]
-struct CargoCommandInputs {
+struct Invocation {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cwd: Option<String>,
+    /// Kill cargo if it runs longer than this many seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Rustup toolchain to run cargo with, e.g. `nightly` or `1.75.0`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toolchain: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
-struct CargoTestInputs {
+struct CargoCommandInputs {
+    #[serde(flatten)]
+    pub invocation: Invocation,
+    #[serde(flatten)]
+    pub selection: BuildSelection,
+    /// Attach a short explanation of each distinct error code (e.g. `E0425`) in the result
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub cwd: Option<String>,
+    pub explain: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct CargoTestInputs {
+    #[serde(flatten)]
+    pub invocation: Invocation,
+    #[serde(flatten)]
+    pub selection: BuildSelection,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub test_arg: Option<String>,
+    /// Attach a short explanation of each distinct error code (e.g. `E0425`) in the result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explain: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
-struct CargoAddInputs {
+struct CargoClippyInputs {
+    #[serde(flatten)]
+    pub invocation: Invocation,
+    #[serde(flatten)]
+    pub selection: BuildSelection,
+    /// Lints to allow, e.g. `clippy::too_many_arguments`
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub cwd: Option<String>,
+    pub allow: Option<Vec<String>>,
+    /// Lints to warn about, e.g. `clippy::pedantic`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub warn: Option<Vec<String>>,
+    /// Lints to turn into errors, e.g. `warnings`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deny: Option<Vec<String>>,
+    /// Apply clippy's machine-applicable suggestions (`--fix`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fix: Option<bool>,
+    /// Let `fix` modify files even if the working tree has uncommitted changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_dirty: Option<bool>,
+    /// Attach a short explanation of each distinct error code (e.g. `E0425`) in the result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explain: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct CargoFmtInputs {
+    #[serde(flatten)]
+    pub invocation: Invocation,
+    /// Only report what would change instead of formatting files in place
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub check: Option<bool>,
+    /// Packages to format (`-p`); defaults to the current package
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<Vec<String>>,
+    /// Format every package in the workspace (`--all`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub all: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct CargoMetadataInputs {
+    #[serde(flatten)]
+    pub invocation: Invocation,
+    /// Also resolve dependencies, adding resolved versions and the full dependency graph.
+    /// Fails if the dependencies have not been downloaded yet.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolve: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct CargoTreeInputs {
+    #[serde(flatten)]
+    pub invocation: Invocation,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub query: Option<TreeQuery>,
+    /// Package the query is about, as a name or `name@version`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    /// Include feature edges (`-e features`) to see which features pull dependencies in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<bool>,
+    /// Limit the depth of `deps` trees
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depth: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct CargoAddInputs {
+    #[serde(flatten)]
+    pub invocation: Invocation,
     pub package: String,
+    /// More flags, e.g. `--dev` or `--features`. Flags that could make cargo run other
+    /// programs, like `--config`, and paths outside the workspace are rejected.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_args: Option<Vec<String>>,
 }
@@ -44,213 +164,1040 @@ struct SetCwdResult {
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
-struct CargoCleanInputs {
+struct SetToolchainInputs {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toolchain: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct SetToolchainResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toolchain: Option<String>,
+    /// The rustc that commands will now use in the session's working directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rustc_version: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct CaptureBaselineInputs {
+    #[serde(flatten)]
+    pub invocation: Invocation,
+    /// `check` (the default) or `clippy`
+    #[serde(default)]
+    pub command: BaselineCommand,
+    #[serde(flatten)]
+    pub selection: BuildSelection,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct CaptureBaselineResult {
+    pub command: String,
+    /// How many diagnostics the baseline holds
+    pub diagnostics: usize,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct ApplySuggestionsInputs {
+    /// The `result_id` of a `cargo_check` or `cargo_clippy` response; defaults to the latest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result_id: Option<u64>,
+    /// Only suggestions for these lint or error codes, e.g. `unused_imports`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub codes: Option<Vec<String>>,
+    /// Only suggestions that edit this file, e.g. `src/main.rs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct RustcExplainInputs {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cwd: Option<String>,
+    /// Error code to explain, e.g. `E0425`
+    pub code: String,
+    /// Rustup toolchain whose rustc explains the code, e.g. `nightly` or `1.75.0`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub toolchain: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+struct CargoCleanInputs {
+    #[serde(flatten)]
+    pub invocation: Invocation,
+    /// More flags, e.g. `--doc` or `--release`. Flags that could make cargo run other
+    /// programs, like `--config`, and paths outside the workspace are rejected.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_args: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 struct CargoRemoveInputs {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub cwd: Option<String>,
+    #[serde(flatten)]
+    pub invocation: Invocation,
     pub package: String,
+    /// More flags, e.g. `--dev`. Flags that could make cargo run other programs, like
+    /// `--config`, and paths outside the workspace are rejected.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_args: Option<Vec<String>>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 struct CargoRunInputs {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub cwd: Option<String>,
+    #[serde(flatten)]
+    pub invocation: Invocation,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub release: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub args: Option<Vec<String>>,
+    /// Contents to feed to the program's standard input
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, JsonSchema)]
 struct CargoUpdateInputs {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub cwd: Option<String>,
+    #[serde(flatten)]
+    pub invocation: Invocation,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub package: Option<String>,
+    /// More flags, e.g. `--precise` or `--dry-run`. Flags that could make cargo run other
+    /// programs, like `--config`, and paths outside the workspace are rejected.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub extra_args: Option<Vec<String>>,
 }
 
-pub fn build_mcp_server(
-    cwd: Arc<RwLock<Option<String>>>,
-) -> McpServer<ProxyToConductor, impl sacp::JrResponder<ProxyToConductor>> {
-    McpServer::builder("cargo-mcp".to_string())
+/// A connection the cargo tools can be served over
+pub trait ToolLink: JrLink {
+    /// A callback that shows the progress of the cargo command started by `tool` to the user,
+    /// if the connection has a way to
+    fn progress(
+        cx: JrConnectionCx<Self>,
+        session: &SessionState,
+        tool: &str,
+    ) -> Option<ProgressCallback>;
+
+    /// Ask the user whether `tool` may run with `input`, showing them `summary` of what it
+    /// will change
+    fn confirm(
+        cx: JrConnectionCx<Self>,
+        session: &SessionState,
+        tool: &str,
+        summary: String,
+        input: serde_json::Value,
+    ) -> impl Future<Output = anyhow::Result<bool>> + Send;
+}
+
+/// Inside an ACP session, progress is mirrored to the client as a tool call of its own
+impl ToolLink for ProxyToConductor {
+    fn progress(
+        cx: JrConnectionCx<Self>,
+        session: &SessionState,
+        tool: &str,
+    ) -> Option<ProgressCallback> {
+        session
+            .session_id()
+            .filter(|_| session.progress_updates())
+            .map(|session_id| progress::mirror_to_client(cx, session_id, tool.replace('_', " ")))
+    }
+
+    /// The client is asked with `session/request_permission`. If the user always allows the
+    /// tool, later calls in the session are not asked about.
+    async fn confirm(
+        cx: JrConnectionCx<Self>,
+        session: &SessionState,
+        tool: &str,
+        summary: String,
+        input: serde_json::Value,
+    ) -> anyhow::Result<bool> {
+        let Some(session_id) = session.session_id() else {
+            anyhow::bail!("`{tool}` needs the user's permission, but the session has not started");
+        };
+        let decision = permission::request(cx, session_id, tool, summary, input).await?;
+        if decision == Decision::AllowAlways {
+            session.always_allow(tool);
+        }
+        Ok(decision != Decision::Reject)
+    }
+}
+
+/// A plain MCP client has no session to show progress in
+impl ToolLink for McpServerToClient {
+    fn progress(
+        _cx: JrConnectionCx<Self>,
+        _session: &SessionState,
+        _tool: &str,
+    ) -> Option<ProgressCallback> {
+        None
+    }
+
+    /// Nor a way to ask the user, so tools that need permission refuse to run
+    async fn confirm(
+        _cx: JrConnectionCx<Self>,
+        _session: &SessionState,
+        tool: &str,
+        _summary: String,
+        _input: serde_json::Value,
+    ) -> anyhow::Result<bool> {
+        anyhow::bail!("`{tool}` needs the user's permission, which this MCP client cannot give")
+    }
+}
+
+/// Let a call to `tool` with `input` go ahead if the user allows it, failing it otherwise. Calls
+/// the user always allowed in this session, or that match an auto-approve rule of the user
+/// configuration, are not asked about.
+async fn ask_permission<Link: ToolLink>(
+    session: &SessionState,
+    mcp_cx: &McpContext<Link>,
+    tool: &str,
+    input: serde_json::Value,
+) -> anyhow::Result<()> {
+    if session.is_always_allowed(tool) {
+        return Ok(());
+    }
+    let rules = session.user_config()?.permissions.auto_approve;
+    if rules.iter().any(|rule| rule.matches(tool, &input)) {
+        return Ok(());
+    }
+
+    let cwd = session
+        .resolve_cwd(input["cwd"].as_str().map(str::to_string))
+        .await;
+    let summary = permission::summary(tool, &input, cwd.as_deref());
+    if Link::confirm(mcp_cx.connection_cx(), session, tool, summary, input).await? {
+        Ok(())
+    } else {
+        anyhow::bail!("the user did not allow `{tool}` to run")
+    }
+}
+
+/// A tool of the server, which runs `func` for each call. Unlike tools registered with
+/// `tool_fn_mut`, whose calls run in a task of their own, the call runs in the MCP request, so
+/// when the client cancels the request, the call is dropped and its cargo command killed.
+/// `call` turns the call into a `Send` future, see `sacp::tool_fn!`.
+struct CargoTool<P, R, F, C> {
+    name: &'static str,
+    description: &'static str,
+    func: F,
+    call: C,
+    _io: PhantomData<fn(P) -> R>,
+}
+
+impl<P, R, F, C> CargoTool<P, R, F, C> {
+    fn new<Link>(name: &'static str, description: &'static str, func: F, call: C) -> Self
+    where
+        Link: JrLink,
+        F: AsyncFn(P, McpContext<Link>) -> Result<R, sacp::Error> + Send + Sync,
+        C: for<'a> Fn(
+                &'a F,
+                CallInput<P>,
+                McpContext<Link>,
+            ) -> BoxFuture<'a, Result<R, sacp::Error>>
+            + Send
+            + Sync,
+    {
+        Self {
+            name,
+            description,
+            func,
+            call,
+            _io: PhantomData,
+        }
+    }
+}
+
+impl<Link, P, R, F, C> McpTool<Link> for CargoTool<P, R, F, C>
+where
+    Link: JrLink,
+    P: JsonSchema + DeserializeOwned + Send + 'static,
+    R: JsonSchema + Serialize + Send + 'static,
+    F: AsyncFn(P, McpContext<Link>) -> Result<R, sacp::Error> + Send + Sync,
+    C: for<'a> Fn(&'a F, CallInput<P>, McpContext<Link>) -> BoxFuture<'a, Result<R, sacp::Error>>
+        + Send
+        + Sync,
+{
+    type Input = CallInput<P>;
+    type Output = R;
+
+    fn name(&self) -> String {
+        self.name.to_string()
+    }
+
+    fn description(&self) -> String {
+        self.description.to_string()
+    }
+
+    fn call_tool(
+        &self,
+        input: CallInput<P>,
+        context: McpContext<Link>,
+    ) -> impl Future<Output = Result<R, sacp::Error>> + Send {
+        (self.call)(&self.func, input, context)
+    }
+}
+
+/// The `call` of the [`CargoTool`] `$tool`, which asks the user before each call if `$policy`
+/// wants it confirmed or, with the session's permission prompts on, if it changes the
+/// workspace, and stops the call if the client cancels it. `$check`, if given, checks the
+/// input first, so the user is not asked about a call that would fail anyway. Adds `$tool` to
+/// `$names`, the tools registered so far.
+macro_rules! call_with_policy {
+    ($names:ident, $session:ident, $policy:ident, $tool:literal $(, $check:ident)?) => {{
+        $names.push($tool);
+        let confirm = $policy.requires_confirmation($tool)
+            || ($session.permission_prompts() && permission::ASK_BY_DEFAULT.contains(&$tool));
+        let session = $session.clone();
+        move |func, call: CallInput<_>, mcp_cx: McpContext<Link>| {
+            let session = session.clone();
+            Box::pin(async move {
+                let cancelled = call
+                    .request_id
+                    .and_then(|id| session.tool_calls().token(&id))
+                    .unwrap_or_default();
+                let input = call.input;
+                let run = async {
+                    $($check(&session, $tool, &input).await?;)?
+                    if confirm {
+                        let value = serde_json::to_value(&input).map_err(anyhow::Error::from)?;
+                        ask_permission(&session, &mcp_cx, $tool, value).await?;
+                    }
+                    func(input, mcp_cx).await
+                };
+                match cancelled.run_until_cancelled(run).await {
+                    Some(result) => result,
+                    None => {
+                        Err(anyhow::anyhow!("the client cancelled the call to `{}`", $tool).into())
+                    }
+                }
+            })
+        }
+    }};
+}
+
+/// Inputs of tools that pass `extra_args` on to cargo
+trait ExtraArgsInput {
+    /// The arguments after the cargo subcommand, ending with the `extra_args`
+    fn cargo_args(&self) -> Vec<&str>;
+    fn extra_args(&self) -> Option<&[String]>;
+    fn cwd(&self) -> Option<String>;
+}
+
+macro_rules! extra_args_input {
+    ($inputs:ty, |$input:ident| $args:expr) => {
+        impl ExtraArgsInput for $inputs {
+            fn cargo_args(&self) -> Vec<&str> {
+                let $input = self;
+                let mut args: Vec<&str> = $args;
+                args.extend(self.extra_args.iter().flatten().map(String::as_str));
+                args
+            }
+
+            fn extra_args(&self) -> Option<&[String]> {
+                self.extra_args.as_deref()
+            }
+
+            fn cwd(&self) -> Option<String> {
+                self.invocation.cwd.clone()
+            }
+        }
+    };
+}
+
+extra_args_input!(CargoAddInputs, |input| vec![&input.package]);
+extra_args_input!(CargoCleanInputs, |_input| vec![]);
+extra_args_input!(CargoRemoveInputs, |input| vec![&input.package]);
+extra_args_input!(CargoUpdateInputs, |input| match &input.package {
+    Some(package) => vec!["-p", package],
+    None => vec![],
+});
+
+/// Check the arguments a call to `tool` with `input` would pass to cargo, see [`check_args`]
+async fn check_input(
+    session: &SessionState,
+    tool: &str,
+    input: &impl ExtraArgsInput,
+) -> Result<(), sacp::Error> {
+    let cwd = session.resolve_cwd(input.cwd()).await;
+    let config = session.config(cwd.as_deref().map(Path::new))?;
+    let args = input.cargo_args();
+    check_args(&config, tool, &args, input.extra_args(), cwd.as_deref()).await
+}
+
+/// Check `args`, which `tool` is about to pass to cargo and of which `extra` came from the tool
+/// call's `extra_args`. The flags must be ones the subcommand accepts and the configuration
+/// allows, and paths must stay inside the workspace.
+async fn check_args(
+    config: &Config,
+    tool: &str,
+    args: &[&str],
+    extra: Option<&[String]>,
+    cwd: Option<&str>,
+) -> Result<(), sacp::Error> {
+    let paths = extra_args::check(tool, args)?;
+    config.check_extra_args(tool, extra.unwrap_or_default())?;
+    if !paths.is_empty() {
+        let workspace = metadata::load_workspace(cwd).await?;
+        let root = Path::new(&workspace.workspace_root);
+        extra_args::check_paths(tool, &paths, Path::new(cwd.unwrap_or(".")), root)?;
+    }
+    Ok(())
+}
+
+/// Limits for a cargo command started by `tool`. The timeout given in the tool call wins
+/// over the project configuration, and the command is killed if the user cancels the turn.
+/// Progress is mirrored to the client unless the session has that turned off. The toolchain
+/// from the tool call or the session is checked to be installed. Also returns the
+/// configuration, which holds the budget the result has to fit before it goes back to the
+/// agent.
+async fn command_options<Link: ToolLink>(
+    session: &SessionState,
+    mcp_cx: &McpContext<Link>,
+    tool: &str,
+    cwd: Option<&str>,
+    invocation: &Invocation,
+) -> anyhow::Result<(CargoCommandOptions, Config)> {
+    let toolchain = session
+        .resolve_toolchain(invocation.toolchain.clone())
+        .await;
+    if let Some(toolchain) = &toolchain {
+        toolchain::ensure_installed(toolchain).await?;
+    }
+    let config = session.config(cwd.map(Path::new))?;
+    let timeout = match invocation.timeout_secs {
+        Some(secs) => Duration::from_secs(secs),
+        None => config.timeout_for(tool),
+    };
+    let options = CargoCommandOptions {
+        timeout: Some(timeout),
+        cancellation: Some(session.cancellation_token()),
+        progress: Link::progress(mcp_cx.connection_cx(), session, tool),
+        toolchain,
+        ..Default::default()
+    };
+    Ok((options, config))
+}
+
+/// With `explain`, attach short explanations of the error codes in `result`
+async fn attach_explanations(
+    result: &mut CargoCommandJsonResult,
+    explain: Option<bool>,
+    toolchain: Option<&str>,
+    cwd: Option<&str>,
+) {
+    if explain.unwrap_or(false) {
+        result.explanations = explain::summaries(result, toolchain, cwd).await;
+    }
+}
+
+/// Keep the complete result for `get_cargo_result`, then build the tool's response from it
+/// with `respond` and trim the response to the budget
+fn store_and_trim<T: ToolResponse>(
+    session: &SessionState,
+    result: CargoCommandJsonResult,
+    budget: &OutputBudget,
+    respond: impl FnOnce(CargoCommandJsonResult) -> T,
+) -> T {
+    let id = session.results().insert(&result, None);
+    let mut response = respond(result);
+    response.command_mut().result_id = Some(id);
+    fit_to_budget(&mut response, budget);
+    response
+}
+
+/// Like [`store_and_trim`] for a check or clippy result, also leaving out of the response the
+/// diagnostics that the session's baseline for `command` already has, if it was captured with
+/// the same `args`. The result is stored with the root of the workspace in `cwd`, so its
+/// suggestions can be applied later.
+async fn store_compare_and_trim(
+    session: &SessionState,
+    command: BaselineCommand,
+    args: &[&str],
+    result: &mut CargoCommandJsonResult,
+    cwd: Option<&str>,
+    budget: &OutputBudget,
+) {
+    let root = metadata::workspace_root(cwd).await.ok();
+    result.result_id = Some(session.results().insert(result, root));
+    if let Some(baseline) = session.baseline(command, args) {
+        result.baseline = Some(BaselineComparison::apply(&baseline, result));
+    }
+    fit_to_budget(result, budget);
+}
+
+/// The stored check or clippy result to take suggestions from: the one with `result_id`, or
+/// else the latest
+fn suggestion_source(
+    session: &SessionState,
+    result_id: Option<u64>,
+) -> anyhow::Result<StoredResult> {
+    let is_build = |result: &CargoCommandJsonResult| {
+        matches!(
+            suggestions::subcommand(&result.command),
+            Some("check" | "clippy")
+        )
+    };
+    let Some(id) = result_id else {
+        return session.results().latest(is_build).ok_or_else(|| {
+            anyhow::anyhow!("no suggestions to apply; run `cargo_check` or `cargo_clippy` first")
+        });
+    };
+    match session.results().get(id) {
+        Some(stored) if is_build(&stored.result) => Ok(stored),
+        Some(stored) => anyhow::bail!(
+            "result {id} is from `{}`, not `cargo check` or `cargo clippy`",
+            stored.result.command
+        ),
+        None => anyhow::bail!("no result {id}; run `cargo_check` or `cargo_clippy` again"),
+    }
+}
+
+/// Refuse to let `--fix` rewrite files unless git says the working tree is clean, so the
+/// changes can be reviewed and reverted separately from the agent's own edits
+async fn ensure_clean_tree(cwd: Option<&str>) -> anyhow::Result<()> {
+    let mut cmd = tokio::process::Command::new("git");
+    cmd.args(["status", "--porcelain"]);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    let output = cmd.output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "cannot check for uncommitted changes: {}Pass `allow_dirty` to fix anyway.",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let dirty = String::from_utf8_lossy(&output.stdout);
+    if !dirty.trim().is_empty() {
+        anyhow::bail!(
+            "the working tree has uncommitted changes:\n{dirty}Commit them or pass `allow_dirty` to fix anyway."
+        );
+    }
+    Ok(())
+}
+
+/// Validate `selection` against the workspace in `cwd` and turn it into cargo arguments
+async fn selection_args(
+    selection: &BuildSelection,
+    cwd: Option<&str>,
+) -> anyhow::Result<Vec<String>> {
+    if selection.is_empty() {
+        return Ok(vec![]);
+    }
+    selection.validate(&metadata::load_workspace(cwd).await?)?;
+    Ok(selection.args())
+}
+
+/// The cargo tools for `session`, as far as both `policy` and the session's configuration allow
+/// them. A tool either of them wants confirmed only runs once the user allowed the call, so an
+/// operator's policy cannot be loosened by a project's configuration.
+pub fn build_mcp_server<Link: ToolLink>(
+    session: Arc<SessionState>,
+    policy: ToolPolicy,
+) -> anyhow::Result<McpServer<Link, impl sacp::JrResponder<Link>>> {
+    // A broken project configuration must not keep the server from starting; the tool calls
+    // that read it report the error instead
+    let tools = match session.config(session.session_cwd().map(Path::new)) {
+        Ok(config) => config.tools,
+        Err(err) => {
+            tracing::warn!(?err, "ignoring the project configuration");
+            session.user_config()?.tools
+        }
+    };
+    let policy = policy.restricted_by(&tools);
+    let mut names = Vec::new();
+    let builder = McpServer::builder("cargo-mcp".to_string())
         .instructions(indoc::indoc! {"
-            Run cargo commands. When possible, always use this instead of calling a shell command. Generally, it makes
-            sense to call `set_cwd` prior to using this, but is not strictly needed if `cwd` is passed to tool calls.
+            Run cargo commands. When possible, always use this instead of calling a shell command. Commands run in the
+            session's working directory by default. Call `set_cwd` to point them somewhere else (e.g. a crate nested in
+            the project), or pass `cwd` to individual tool calls.
         "})
-        .tool_fn_mut(
+        .tool(CargoTool::new(
             "set_cwd",
             indoc::indoc! {r#"
-                Set a global default working directory for subsequent commands when
-                they do not provide an explicit `cwd`.
+                Set the default working directory for subsequent commands in this session when
+                they do not provide an explicit `cwd`. Passing no `cwd` reverts to the session's
+                working directory.
             "#},
             {
-                let cwd = cwd.clone();
-                async move |input: SetCwdInputs, _mcp_cx: McpContext<ProxyToConductor>| {
-                    *cwd.write().await = input.cwd.clone();
+                let session = session.clone();
+                async move |input: SetCwdInputs, _mcp_cx: McpContext<Link>| {
+                    session.set_cwd(input.cwd).await;
 
                     Ok(SetCwdResult {
-                        cwd: cwd.read().await.clone(),
+                        cwd: session.cwd().await,
                     })
                 }
             },
-            sacp::tool_fn_mut!(),
-        )
-        .tool_fn_mut(
+            call_with_policy!(names, session, policy, "set_cwd"),
+        ))
+        .tool(CargoTool::new(
+            "set_toolchain",
+            indoc::indoc! {r#"
+                Set the rustup toolchain (e.g. `nightly` or an MSRV like `1.75.0`) for subsequent
+                commands in this session when they do not provide an explicit `toolchain`. Passing
+                no `toolchain` reverts to the toolchain rustup picks for the directory.
+            "#},
+            {
+                let session = session.clone();
+                async move |input: SetToolchainInputs, _mcp_cx: McpContext<Link>| {
+                    if let Some(toolchain) = &input.toolchain {
+                        toolchain::ensure_installed(toolchain).await?;
+                    }
+                    session.set_toolchain(input.toolchain).await;
+
+                    let toolchain = session.toolchain().await;
+                    let cwd = session.cwd().await;
+                    Ok(SetToolchainResult {
+                        rustc_version: toolchain::rustc_version(
+                            toolchain.as_deref(),
+                            cwd.as_deref(),
+                        )
+                        .await,
+                        toolchain,
+                    })
+                }
+            },
+            call_with_policy!(names, session, policy, "set_toolchain"),
+        ))
+        .tool(CargoTool::new(
+            "capture_baseline",
+            indoc::indoc! {r#"
+                Run `cargo check` (or `cargo clippy`) and record its diagnostics as the session's
+                baseline. From then on, `cargo_check` (or `cargo_clippy`) and the automatic check
+                after each turn report only the diagnostics that are new relative to the
+                baseline, along with counts of the unchanged ones and a list of the fixed ones.
+                Only runs with the same package, target and feature selection are compared.
+            "#},
+            {
+                let session = session.clone();
+                async move |input: CaptureBaselineInputs, mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let args = selection_args(&input.selection, cwd.as_deref()).await?;
+                    let tool = format!("cargo_{}", input.command.subcommand());
+                    let (options, _) = command_options(
+                        &session,
+                        &mcp_cx,
+                        &tool,
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
+
+                    let result =
+                        baseline::capture(&session, input.command, args, cwd, options).await?;
+                    Ok(CaptureBaselineResult {
+                        command: result.command,
+                        diagnostics: result.diagnostics.len(),
+                    })
+                }
+            },
+            call_with_policy!(names, session, policy, "capture_baseline"),
+        ))
+        .tool(CargoTool::new(
             "cargo_check",
             indoc::indoc! {r#"
-                Runs cargo check.
+                Runs cargo check. If the session has a baseline (see `capture_baseline`),
+                `diagnostics` only lists what is new relative to it.
             "#},
             {
-                let cwd = cwd.clone();
-                async move |input: CargoCommandInputs, _mcp_cx: McpContext<ProxyToConductor>| {
-                    let cwd = if input.cwd.is_some() {
-                        input.cwd
-                    } else {
-                        cwd.read().await.clone()
-                    };
+                let session = session.clone();
+                async move |input: CargoCommandInputs, mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let args = selection_args(&input.selection, cwd.as_deref()).await?;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_check",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
 
-                    Ok(execute_cargo_command("check", vec![], cwd, false).await?)
+                    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+                    let baseline_args = args.clone();
+                    let toolchain = options.toolchain.clone();
+                    let mut result =
+                        execute_cargo_command("check", args, cwd.clone(), false, options).await?;
+                    attach_explanations(
+                        &mut result,
+                        input.explain,
+                        toolchain.as_deref(),
+                        cwd.as_deref(),
+                    )
+                    .await;
+                    store_compare_and_trim(
+                        &session,
+                        BaselineCommand::Check,
+                        &baseline_args,
+                        &mut result,
+                        cwd.as_deref(),
+                        &config.output,
+                    )
+                    .await;
+                    Ok(result)
                 }
             },
-            sacp::tool_fn_mut!(),
-        )
-        .tool_fn_mut(
+            call_with_policy!(names, session, policy, "cargo_check"),
+        ))
+        .tool(CargoTool::new(
             "cargo_build",
             indoc::indoc! {r#"
                 Runs cargo build.
             "#},
             {
-                let cwd = cwd.clone();
-                async move |input: CargoCommandInputs, _mcp_cx: McpContext<ProxyToConductor>| {
-                    let cwd = if input.cwd.is_some() {
-                        input.cwd
-                    } else {
-                        cwd.read().await.clone()
-                    };
+                let session = session.clone();
+                async move |input: CargoCommandInputs, mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let args = selection_args(&input.selection, cwd.as_deref()).await?;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_build",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
 
-                    Ok(execute_cargo_command("build", vec![], cwd, false).await?)
+                    let args = args.iter().map(|a| a.as_str()).collect();
+                    let toolchain = options.toolchain.clone();
+                    let mut result =
+                        execute_cargo_command("build", args, cwd.clone(), false, options).await?;
+                    attach_explanations(
+                        &mut result,
+                        input.explain,
+                        toolchain.as_deref(),
+                        cwd.as_deref(),
+                    )
+                    .await;
+                    Ok(store_and_trim(&session, result, &config.output, identity))
                 }
             },
-            sacp::tool_fn_mut!(),
-        )
-        .tool_fn_mut(
+            call_with_policy!(names, session, policy, "cargo_build"),
+        ))
+        .tool(CargoTool::new(
             "cargo_test",
             indoc::indoc! {r#"
                 Runs cargo test. Optionally specify a test name or pattern to run specific tests.
+                The result lists every test with its status and, for failed tests, their output.
             "#},
             {
-                let cwd = cwd.clone();
-                async move |input: CargoTestInputs, _mcp_cx: McpContext<ProxyToConductor>| {
-                    let args = if let Some(test_arg) = input.test_arg.as_deref() {
-                        vec![test_arg]
-                    } else {
-                        vec![]
-                    };
+                let session = session.clone();
+                async move |input: CargoTestInputs, mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let selection = selection_args(&input.selection, cwd.as_deref()).await?;
+                    let mut args: Vec<&str> = selection.iter().map(|a| a.as_str()).collect();
+                    if let Some(test_arg) = input.test_arg.as_deref() {
+                        args.push(test_arg);
+                    }
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_test",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
+                    if libtest::supports_json_format(options.toolchain.as_deref(), cwd.as_deref())
+                        .await
+                    {
+                        args.push("--");
+                        args.extend(libtest::JSON_FORMAT_ARGS);
+                    }
 
-                    let cwd = if input.cwd.is_some() {
-                        input.cwd
-                    } else {
-                        cwd.read().await.clone()
-                    };
+                    let toolchain = options.toolchain.clone();
+                    let mut result =
+                        execute_cargo_command("test", args, cwd.clone(), false, options).await?;
+                    attach_explanations(
+                        &mut result,
+                        input.explain,
+                        toolchain.as_deref(),
+                        cwd.as_deref(),
+                    )
+                    .await;
+                    Ok(store_and_trim(
+                        &session,
+                        result,
+                        &config.output,
+                        CargoTestResult::from,
+                    ))
+                }
+            },
+            call_with_policy!(names, session, policy, "cargo_test"),
+        ))
+        .tool(CargoTool::new(
+            "cargo_clippy",
+            indoc::indoc! {r#"
+                Runs cargo clippy and returns the same structured diagnostics as `cargo_check`.
+                Lints can be allowed, warned about or denied by name. With `fix`, machine-applicable
+                suggestions are applied, which requires a clean git working tree unless
+                `allow_dirty` is set. Compared to the session's clippy baseline like `cargo_check`.
+            "#},
+            {
+                let session = session.clone();
+                async move |input: CargoClippyInputs, mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let selection = selection_args(&input.selection, cwd.as_deref()).await?;
 
-                    Ok(execute_cargo_command("test", args, cwd, false).await?)
+                    let mut args: Vec<&str> = selection.iter().map(|a| a.as_str()).collect();
+                    if input.fix.unwrap_or(false) {
+                        args.push("--fix");
+                        if input.allow_dirty.unwrap_or(false) {
+                            args.extend(["--allow-dirty", "--allow-staged", "--allow-no-vcs"]);
+                        } else {
+                            ensure_clean_tree(cwd.as_deref()).await?;
+                        }
+                    }
+                    let lints = [("-A", &input.allow), ("-W", &input.warn), ("-D", &input.deny)];
+                    if lints.iter().any(|(_, names)| names.is_some()) {
+                        args.push("--");
+                        for (flag, names) in lints {
+                            for name in names.iter().flatten() {
+                                args.extend([flag, name.as_str()]);
+                            }
+                        }
+                    }
+
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_clippy",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
+
+                    let baseline_args = args.clone();
+                    let toolchain = options.toolchain.clone();
+                    let mut result =
+                        execute_cargo_command("clippy", args, cwd.clone(), false, options).await?;
+                    attach_explanations(
+                        &mut result,
+                        input.explain,
+                        toolchain.as_deref(),
+                        cwd.as_deref(),
+                    )
+                    .await;
+                    store_compare_and_trim(
+                        &session,
+                        BaselineCommand::Clippy,
+                        &baseline_args,
+                        &mut result,
+                        cwd.as_deref(),
+                        &config.output,
+                    )
+                    .await;
+                    Ok(result)
                 }
             },
-            sacp::tool_fn_mut!(),
-        )
-        .tool_fn_mut(
-            "cargo_add",
+            call_with_policy!(names, session, policy, "cargo_clippy"),
+        ))
+        .tool(CargoTool::new(
+            "cargo_fmt",
             indoc::indoc! {r#"
-                Runs `cargo add <package> [extra args]`.
+                Runs cargo fmt. Formats files in place and lists the files that changed, or with
+                `check` leaves them alone and returns a unified diff for each file that would
+                change. `error` is set if rustfmt itself failed, e.g. on an invalid rustfmt.toml.
             "#},
             {
-                let cwd = cwd.clone();
-                async move |input: CargoAddInputs, _mcp_cx: McpContext<ProxyToConductor>| {
+                let session = session.clone();
+                async move |input: CargoFmtInputs, mcp_cx: McpContext<Link>| {
+                    let check = input.check.unwrap_or(false);
                     let mut args: Vec<&str> = Vec::new();
-                    args.push(&input.package);
-                    if let Some(extra) = &input.extra_args {
-                        args.extend(extra.iter().map(|s| s.as_str()));
+                    if check {
+                        args.push("--check");
+                    }
+                    for package in input.package.iter().flatten() {
+                        args.extend(["-p", package.as_str()]);
+                    }
+                    if input.all.unwrap_or(false) {
+                        args.push("--all");
+                    }
+                    args.extend(["--", "--color", "never"]);
+                    if !check {
+                        // List the files that were rewritten
+                        args.push("-l");
                     }
 
-                    let cwd = if input.cwd.is_some() {
-                        input.cwd
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_fmt",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
+
+                    let result = execute_cargo_command("fmt", args, cwd, true, options).await?;
+                    let respond = if check {
+                        CargoFmtResult::from_check
                     } else {
-                        cwd.read().await.clone()
+                        CargoFmtResult::from_write
                     };
+                    Ok(store_and_trim(&session, result, &config.output, respond))
+                }
+            },
+            call_with_policy!(names, session, policy, "cargo_fmt"),
+        ))
+        .tool(CargoTool::new(
+            "cargo_metadata",
+            indoc::indoc! {r#"
+                Describes the workspace: its member packages with their targets, features and
+                direct dependencies. Set `resolve` to also get the resolved dependency versions and
+                the full dependency graph.
+            "#},
+            {
+                let session = session.clone();
+                async move |input: CargoMetadataInputs, mcp_cx: McpContext<Link>| {
+                    let mut args = vec!["--format-version", "1", "--offline"];
+                    if !input.resolve.unwrap_or(false) {
+                        args.push("--no-deps");
+                    }
 
-                    Ok(execute_cargo_command("add", args, cwd, false).await?)
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_metadata",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
+
+                    let result = execute_cargo_command("metadata", args, cwd, true, options).await?;
+                    Ok(store_and_trim(
+                        &session,
+                        result,
+                        &config.output,
+                        CargoMetadataResult::from,
+                    ))
                 }
             },
-            sacp::tool_fn_mut!(),
-        )
-        .tool_fn_mut(
-            "cargo_clean",
+            call_with_policy!(names, session, policy, "cargo_metadata"),
+        ))
+        .tool(CargoTool::new(
+            "cargo_tree",
             indoc::indoc! {r#"
-                Runs `cargo clean [extra args]`.
+                Queries the dependency graph with cargo tree. `deps` (the default) returns what
+                `package`, or the whole workspace, depends on as a nested tree. `why` returns the
+                paths from workspace members down to `package`. `duplicates` lists packages built
+                in several versions and who depends on each version.
             "#},
             {
-                let cwd = cwd.clone();
-                async move |input: CargoCleanInputs, _mcp_cx: McpContext<ProxyToConductor>| {
-                    let mut args: Vec<&str> = Vec::new();
-                    if let Some(extra) = &input.extra_args {
-                        args.extend(extra.iter().map(|s| s.as_str()));
+                let session = session.clone();
+                async move |input: CargoTreeInputs, mcp_cx: McpContext<Link>| {
+                    let query = input.query.unwrap_or_default();
+                    let mut args = query.args(input.package.as_deref())?;
+                    if input.features.unwrap_or(false) {
+                        args.extend(["-e", "features"]);
+                    }
+                    let depth = input.depth.map(|d| d.to_string());
+                    if let Some(depth) = &depth {
+                        args.extend(["--depth", depth.as_str()]);
                     }
 
-                    let cwd = if input.cwd.is_some() {
-                        input.cwd
-                    } else {
-                        cwd.read().await.clone()
-                    };
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_tree",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
 
-                    Ok(execute_cargo_command("clean", args, cwd, true).await?)
+                    let result = execute_cargo_command("tree", args, cwd, true, options).await?;
+                    Ok(store_and_trim(&session, result, &config.output, |result| {
+                        CargoTreeResult::new(result, query)
+                    }))
                 }
             },
-            sacp::tool_fn_mut!(),
-        )
-        .tool_fn_mut(
+            call_with_policy!(names, session, policy, "cargo_tree"),
+        ))
+        .tool(CargoTool::new(
+            "cargo_add",
+            indoc::indoc! {r#"
+                Runs `cargo add <package> [extra args]`.
+            "#},
+            {
+                let session = session.clone();
+                async move |input: CargoAddInputs, mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_add",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
+
+                    let args = input.cargo_args();
+                    let result = execute_cargo_command("add", args, cwd, false, options).await?;
+                    Ok(store_and_trim(&session, result, &config.output, identity))
+                }
+            },
+            call_with_policy!(names, session, policy, "cargo_add", check_input),
+        ))
+        .tool(CargoTool::new(
+            "cargo_clean",
+            indoc::indoc! {r#"
+                Runs `cargo clean [extra args]`.
+            "#},
+            {
+                let session = session.clone();
+                async move |input: CargoCleanInputs, mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_clean",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
+
+                    let args = input.cargo_args();
+                    let result = execute_cargo_command("clean", args, cwd, true, options).await?;
+                    Ok(store_and_trim(&session, result, &config.output, identity))
+                }
+            },
+            call_with_policy!(names, session, policy, "cargo_clean", check_input),
+        ))
+        .tool(CargoTool::new(
             "cargo_remove",
             indoc::indoc! {r#"
                 Runs `cargo remove <package> [extra args]`.
             "#},
             {
-                let cwd = cwd.clone();
-                async move |input: CargoRemoveInputs, _mcp_cx: McpContext<ProxyToConductor>| {
-                    let mut args: Vec<&str> = Vec::new();
-                    args.push(&input.package);
-                    if let Some(extra) = &input.extra_args {
-                        args.extend(extra.iter().map(|s| s.as_str()));
-                    }
+                let session = session.clone();
+                async move |input: CargoRemoveInputs, mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_remove",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
 
-                    let cwd = if input.cwd.is_some() {
-                        input.cwd
-                    } else {
-                        cwd.read().await.clone()
-                    };
-
-                    Ok(execute_cargo_command("remove", args, cwd, true).await?)
+                    let args = input.cargo_args();
+                    let result = execute_cargo_command("remove", args, cwd, true, options).await?;
+                    Ok(store_and_trim(&session, result, &config.output, identity))
                 }
             },
-            sacp::tool_fn_mut!(),
-        )
-        .tool_fn_mut(
+            call_with_policy!(names, session, policy, "cargo_remove", check_input),
+        ))
+        .tool(CargoTool::new(
             "cargo_run",
             indoc::indoc! {r#"
-                Runs `cargo run [extra args]`.
+                Runs `cargo run [extra args]`. The program's output is returned in
+                `program_stdout` and `program_stderr`, apart from cargo's build messages; `signal`
+                is set when the program was killed by a signal. `stdin` is fed to the program.
             "#},
             {
-                let cwd = cwd.clone();
-                async move |input: CargoRunInputs, _mcp_cx: McpContext<ProxyToConductor>| {
+                let session = session.clone();
+                async move |input: CargoRunInputs, mcp_cx: McpContext<Link>| {
                     let mut args: Vec<&str> = Vec::new();
                     if input.release.unwrap_or(false) {
                         args.push("--release");
@@ -259,44 +1206,136 @@ pub fn build_mcp_server(
                         args.extend(a.iter().map(|s| s.as_str()));
                     }
 
-                    let cwd = if input.cwd.is_some() {
-                        input.cwd
-                    } else {
-                        cwd.read().await.clone()
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_run",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
+                    let options = CargoCommandOptions {
+                        stdin: input.stdin,
+                        ..options
                     };
 
-                    Ok(execute_cargo_command("run", args, cwd, true).await?)
+                    let result = execute_cargo_command("run", args, cwd, false, options).await?;
+                    Ok(store_and_trim(
+                        &session,
+                        result,
+                        &config.output,
+                        CargoRunResult::from,
+                    ))
                 }
             },
-            sacp::tool_fn_mut!(),
-        )
-        .tool_fn_mut(
+            call_with_policy!(names, session, policy, "cargo_run"),
+        ))
+        .tool(CargoTool::new(
             "cargo_update",
             indoc::indoc! {r#"
                 Runs `cargo update`. Optionally specify `package` (uses `-p`) and extra args.
             "#},
             {
-                let cwd = cwd.clone();
-                async move |input: CargoUpdateInputs, _mcp_cx: McpContext<ProxyToConductor>| {
-                    let mut args: Vec<&str> = Vec::new();
-                    if let Some(pkg) = input.package.as_deref() {
-                        args.push("-p");
-                        args.push(pkg);
-                    }
-                    if let Some(extra) = &input.extra_args {
-                        args.extend(extra.iter().map(|s| s.as_str()));
-                    }
+                let session = session.clone();
+                async move |input: CargoUpdateInputs, mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.invocation.cwd.clone()).await;
+                    let (options, config) = command_options(
+                        &session,
+                        &mcp_cx,
+                        "cargo_update",
+                        cwd.as_deref(),
+                        &input.invocation,
+                    )
+                    .await?;
 
-                    let cwd = if input.cwd.is_some() {
-                        input.cwd
-                    } else {
-                        cwd.read().await.clone()
+                    let args = input.cargo_args();
+                    let result = execute_cargo_command("update", args, cwd, true, options).await?;
+                    Ok(store_and_trim(&session, result, &config.output, identity))
+                }
+            },
+            call_with_policy!(names, session, policy, "cargo_update", check_input),
+        ))
+        .tool(CargoTool::new(
+            "rustc_explain",
+            indoc::indoc! {r#"
+                Runs `rustc --explain <code>` and returns the full explanation of an error code
+                like `E0425`, with erroneous and fixed examples.
+            "#},
+            {
+                let session = session.clone();
+                async move |input: RustcExplainInputs, _mcp_cx: McpContext<Link>| {
+                    let cwd = session.resolve_cwd(input.cwd).await;
+                    let toolchain = session.resolve_toolchain(input.toolchain).await;
+                    if let Some(toolchain) = &toolchain {
+                        toolchain::ensure_installed(toolchain).await?;
+                    }
+                    Ok(explain::explain(&input.code, toolchain.as_deref(), cwd.as_deref()).await?)
+                }
+            },
+            call_with_policy!(names, session, policy, "rustc_explain"),
+        ))
+        .tool(CargoTool::new(
+            "cargo_apply_suggestions",
+            indoc::indoc! {r#"
+                Apply the machine-applicable suggestions of the latest `cargo_check` or
+                `cargo_clippy` run (or the one with `result_id`), optionally only those for some
+                lint `codes` or in one `file`. Suggestions whose file changed since the run, or
+                that overlap one already applied, are left out and listed under `rejected`.
+            "#},
+            {
+                let session = session.clone();
+                async move |input: ApplySuggestionsInputs, _mcp_cx: McpContext<Link>| {
+                    let source = suggestion_source(&session, input.result_id)?;
+                    let root = source.workspace_root.as_deref().ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "the workspace of result {} is unknown; run `{}` again",
+                            source.id,
+                            source.result.command
+                        )
+                    })?;
+                    let filter = SuggestionFilter {
+                        codes: input.codes.as_deref(),
+                        file: input.file.as_deref(),
                     };
-
-                    Ok(execute_cargo_command("update", args, cwd, true).await?)
+                    let applied = suggestions::apply(&source, root, &filter)?;
+                    session.record_rs_edits(suggestions::edited_files(&applied, root));
+                    Ok(applied)
                 }
             },
-            sacp::tool_fn_mut!(),
-        )
-        .build()
+            call_with_policy!(names, session, policy, "cargo_apply_suggestions"),
+        ))
+        .tool(CargoTool::new(
+            "get_cargo_result",
+            indoc::indoc! {r#"
+                Fetch more of an earlier cargo command's result by its `result_id`, e.g. the
+                diagnostics or output that were left out of a `truncated` response. Pages through
+                the `diagnostics` (optionally only those of a `level` or in a `file`), `stdout` or
+                `stderr` with `offset` and `limit`; `next_offset` is where the next page starts.
+            "#},
+            {
+                let session = session.clone();
+                async move |input: ResultQuery, _mcp_cx: McpContext<Link>| {
+                    Ok(session.results().page(&input)?)
+                }
+            },
+            call_with_policy!(names, session, policy, "get_cargo_result"),
+        ));
+
+    if let Some(unknown) = policy.confirm.iter().find(|t| !names.contains(&t.as_str())) {
+        anyhow::bail!("invalid `tools.confirm` configuration: unknown tool `{unknown}`");
+    }
+    let builder = match &policy.enabled {
+        Some(enabled) => enabled
+            .iter()
+            .try_fold(builder.disable_all_tools(), |b, name| b.enable_tool(name))
+            .context("invalid `tools.enabled` configuration")?,
+        None => builder,
+    };
+    let builder = policy
+        .disabled
+        .iter()
+        .try_fold(builder, |b, name| b.disable_tool(name))
+        .context("invalid `tools.disabled` configuration")?;
+    Ok(builder.build())
 }
@@ -0,0 +1,715 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::baseline::BaselineCommand;
+use crate::cargo_command::OutputBudget;
+use crate::extra_args::{ExtraArgsError, Rejection};
+
+/// Location of the project configuration, relative to the project directory
+pub const PROJECT_CONFIG_PATH: &str = ".symposium/cargo.toml";
+
+/// Location of the user configuration, relative to the user's configuration directory
+pub const USER_CONFIG_PATH: &str = "symposium/cargo.toml";
+
+/// Timeout for cargo commands when neither the tool call nor the configuration sets one
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// Settings read from the user and project configuration files, see [`ConfigSources`]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Directory commands run in by default, relative to the directory the session was
+    /// started in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    #[serde(default)]
+    pub tools: ToolPolicy,
+    #[serde(default)]
+    pub auto_check: AutoCheckConfig,
+    /// Seconds a cargo command may run before it is killed, keyed by tool name
+    /// (e.g. `cargo_run`), `auto_check`, or `default` for everything else
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub timeouts: BTreeMap<String, u64>,
+    /// How large tool results may get before they are trimmed
+    #[serde(default)]
+    pub output: OutputBudget,
+    #[serde(default)]
+    pub baseline: BaselineConfig,
+    #[serde(default)]
+    pub permissions: PermissionsConfig,
+    /// Flags allowed in the `extra_args` of a tool, keyed by tool name (e.g. `cargo_update`).
+    /// Tools that are not listed accept any flag.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra_args: BTreeMap<String, Vec<String>>,
+}
+
+/// Which tools the MCP server offers, and which of them ask the user before they run
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ToolPolicy {
+    /// Only offer these tools
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<Vec<String>>,
+    /// Leave these tools out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub disabled: Vec<String>,
+    /// Offer these tools, but only run them once the user allowed the call
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub confirm: Vec<String>,
+}
+
+impl ToolPolicy {
+    /// A policy that allows only what both `self` and `other` allow, and confirms every tool
+    /// either of them confirms, so neither can loosen the other
+    pub fn restricted_by(&self, other: &ToolPolicy) -> ToolPolicy {
+        let enabled = match (&self.enabled, &other.enabled) {
+            (Some(a), Some(b)) => Some(a.iter().filter(|t| b.contains(t)).cloned().collect()),
+            (a, b) => a.clone().or_else(|| b.clone()),
+        };
+        let union = |a: &[String], b: &[String]| {
+            let mut all = a.to_vec();
+            all.extend(b.iter().filter(|t| !a.contains(t)).cloned());
+            all
+        };
+        ToolPolicy {
+            enabled,
+            disabled: union(&self.disabled, &other.disabled),
+            confirm: union(&self.confirm, &other.confirm),
+        }
+    }
+
+    /// Whether calls to `tool` need the user's permission
+    pub fn requires_confirmation(&self, tool: &str) -> bool {
+        self.confirm.iter().any(|t| t == tool)
+    }
+}
+
+/// When tools that change the workspace may run without asking the user. Only read from the
+/// user configuration and the command line, since the agent can edit the project's.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PermissionsConfig {
+    /// Calls that are allowed without asking
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub auto_approve: Vec<AutoApproveRule>,
+}
+
+/// Calls to one tool that are allowed without asking the user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AutoApproveRule {
+    /// The tool, e.g. `cargo_update`
+    pub tool: String,
+    /// Only calls for one of these packages; calls without a `package` are asked about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub packages: Option<Vec<String>>,
+    /// Also calls with `extra_args`, which are otherwise asked about
+    #[serde(default)]
+    pub extra_args: bool,
+}
+
+impl AutoApproveRule {
+    /// Whether the rule allows calling `tool` with `input`, the arguments of the call
+    pub fn matches(&self, tool: &str, input: &serde_json::Value) -> bool {
+        let package = self.packages.as_ref().is_none_or(|packages| {
+            input["package"]
+                .as_str()
+                .is_some_and(|package| packages.iter().any(|p| p == package))
+        });
+        let extra_args = self.extra_args
+            || input["extra_args"]
+                .as_array()
+                .is_none_or(|args| args.is_empty());
+        self.tool == tool && package && extra_args
+    }
+}
+
+/// Baselines of pre-existing diagnostics that results are compared to
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BaselineConfig {
+    /// Commands whose diagnostics are captured as a baseline when a session starts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub at_session_start: Vec<BaselineCommand>,
+}
+
+/// What runs at the end of a turn in which the agent edited Rust files
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AutoCheckConfig {
+    /// Whether to check at all
+    #[serde(default = "default_auto_check_enabled")]
+    pub enabled: bool,
+    /// How many times in a row the agent is prompted to fix a failing check
+    #[serde(default = "default_max_followups")]
+    pub max_followups: usize,
+    /// Stages run in order; the first failing stage stops the pipeline
+    #[serde(default = "default_stages")]
+    pub stages: Vec<Stage>,
+}
+
+impl Default for AutoCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: default_auto_check_enabled(),
+            max_followups: default_max_followups(),
+            stages: default_stages(),
+        }
+    }
+}
+
+fn default_auto_check_enabled() -> bool {
+    true
+}
+
+fn default_max_followups() -> usize {
+    3
+}
+
+fn default_stages() -> Vec<Stage> {
+    vec![Stage {
+        command: "check".to_string(),
+        args: vec![],
+        json: None,
+        touched_modules_only: false,
+        timeout_secs: None,
+    }]
+}
+
+/// A single cargo invocation in the verification pipeline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Stage {
+    /// The cargo subcommand, e.g. `clippy`
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    /// Pass `--message-format json`; defaults to whether `command` supports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json: Option<bool>,
+    /// For `test` stages, only run tests in the modules of the edited files
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub touched_modules_only: bool,
+    /// Overrides the `auto_check` timeout for this stage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+}
+
+impl Stage {
+    pub fn uses_json(&self) -> bool {
+        self.json.unwrap_or(matches!(
+            self.command.as_str(),
+            "check" | "clippy" | "build" | "test" | "bench" | "doc"
+        ))
+    }
+
+    /// Arguments to pass to cargo, given the Rust files the agent edited
+    pub fn args_for(
+        &self,
+        edited_files: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Vec<String> {
+        let mut args = self.args.clone();
+        if self.touched_modules_only
+            && let Some(filters) = test_filters(edited_files)
+        {
+            if !args.iter().any(|a| a == "--") {
+                args.push("--".to_string());
+            }
+            args.extend(filters);
+        }
+        args
+    }
+
+    /// How the stage is shown to the agent and the user
+    pub fn label(&self) -> String {
+        std::iter::once(format!("cargo {}", self.command))
+            .chain(self.args.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Module paths (like `parser::lexer`) to filter tests by, or `None` if all tests should
+/// run because an edited file is a crate root or lives outside `src/`
+fn test_filters(edited_files: impl IntoIterator<Item = impl AsRef<Path>>) -> Option<Vec<String>> {
+    let mut filters = Vec::new();
+    for file in edited_files {
+        let components: Vec<_> = file
+            .as_ref()
+            .components()
+            .filter_map(|c| c.as_os_str().to_str())
+            .collect();
+        let src = components.iter().rposition(|c| *c == "src")?;
+        let mut module: Vec<&str> = components[src + 1..].to_vec();
+        let file_name = module.pop()?.strip_suffix(".rs")?;
+        if module.is_empty() && matches!(file_name, "lib" | "main") {
+            return None;
+        }
+        if file_name != "mod" {
+            module.push(file_name);
+        }
+        filters.push(module.join("::"));
+    }
+    filters.sort();
+    filters.dedup();
+    Some(filters)
+}
+
+impl Config {
+    /// Find the closest `.symposium/cargo.toml` at or above `cwd`
+    pub fn find_project_config(cwd: &Path) -> Option<PathBuf> {
+        cwd.ancestors()
+            .map(|dir| dir.join(PROJECT_CONFIG_PATH))
+            .find(|path| path.is_file())
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    /// How long cargo commands started by `tool` may run
+    pub fn timeout_for(&self, tool: &str) -> Duration {
+        self.timeouts
+            .get(tool)
+            .or_else(|| self.timeouts.get("default"))
+            .map(|secs| Duration::from_secs(*secs))
+            .unwrap_or(DEFAULT_TIMEOUT)
+    }
+
+    /// Load the configuration for a project from the default sources
+    pub fn load(cwd: Option<&Path>) -> Result<Config> {
+        ConfigSources::default().load(cwd)
+    }
+
+    /// Fail if `args`, the `extra_args` of `tool`, has a flag the configuration does not allow
+    /// for it. A flag's value may follow it or be attached with `=`.
+    pub fn check_extra_args(&self, tool: &str, args: &[String]) -> Result<(), ExtraArgsError> {
+        let Some(allowed) = self.extra_args.get(tool) else {
+            return Ok(());
+        };
+        for arg in args.iter().filter(|arg| arg.starts_with('-')) {
+            let flag = arg.split_once('=').map_or(arg.as_str(), |(flag, _)| flag);
+            if !allowed.iter().any(|a| a == flag) {
+                return Err(ExtraArgsError::new(
+                    tool,
+                    arg,
+                    Rejection::NotConfigured,
+                    allowed.clone(),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where configuration comes from. Later sources take precedence over earlier ones:
+///
+/// 1. the built-in defaults
+/// 2. the user configuration, `$XDG_CONFIG_HOME/symposium/cargo.toml` by default
+/// 3. the closest `.symposium/cargo.toml` at or above the working directory
+/// 4. overrides, e.g. from the command line
+///
+/// Tables are merged key by key; any other value, including a list, replaces the one from
+/// an earlier source. The exceptions are the project's `[tools]`, which can only disable tools
+/// or ask for confirmation on top of the others, see [`ToolPolicy::restricted_by`], and its
+/// `[extra-args]`, which can only leave out flags the others allow. Values
+/// given in a tool call win over all of them.
+#[derive(Debug, Clone)]
+pub struct ConfigSources {
+    /// The user configuration file, if any
+    pub user: Option<PathBuf>,
+    pub overrides: toml::Table,
+}
+
+impl Default for ConfigSources {
+    fn default() -> Self {
+        Self {
+            user: user_config_path(),
+            overrides: toml::Table::new(),
+        }
+    }
+}
+
+impl ConfigSources {
+    /// Override the setting at `path` (e.g. `["timeouts", "default"]`)
+    pub fn set(&mut self, path: &[&str], value: impl Into<toml::Value>) {
+        let (key, tables) = path.split_last().expect("path is not empty");
+        let mut table = &mut self.overrides;
+        for name in tables {
+            let entry = table
+                .entry(name.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if !entry.is_table() {
+                *entry = toml::Value::Table(toml::Table::new());
+            }
+            table = entry.as_table_mut().expect("just made a table");
+        }
+        table.insert(key.to_string(), value.into());
+    }
+
+    /// The files that apply in `cwd`, in order of precedence, lowest first
+    pub fn files(&self, cwd: Option<&Path>) -> Vec<PathBuf> {
+        self.user
+            .iter()
+            .filter(|path| path.is_file())
+            .cloned()
+            .chain(cwd.and_then(Config::find_project_config))
+            .collect()
+    }
+
+    /// The configuration that applies in `cwd`
+    pub fn load(&self, cwd: Option<&Path>) -> Result<Config> {
+        let mut merged = toml::Table::new();
+        let mut project_tools = None;
+        let mut project_extra_args = None;
+        for path in self.files(cwd) {
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            let table: toml::Table = toml::from_str(&text)
+                .with_context(|| format!("failed to parse {}", path.display()))?;
+            if path.ends_with(PROJECT_CONFIG_PATH) {
+                project_tools = table.get("tools").cloned();
+                project_extra_args = table.get("extra-args").cloned();
+            }
+            merge(&mut merged, table);
+        }
+        merge(&mut merged, self.overrides.clone());
+        let mut config: Config =
+            toml::Value::Table(merged).try_into().with_context(|| {
+                match self.files(cwd).as_slice() {
+                    [] => "invalid configuration".to_string(),
+                    files => format!(
+                        "invalid configuration in {}",
+                        files
+                            .iter()
+                            .map(|f| f.display().to_string())
+                            .collect::<Vec<_>>()
+                            .join(" or ")
+                    ),
+                }
+            })?;
+
+        // The project's `[tools]` only narrows what the user and the command line allow, since
+        // its lists would otherwise replace theirs
+        if let Some(tools) = project_tools {
+            let project: ToolPolicy = tools.try_into().context("invalid project `[tools]`")?;
+            config.tools = self.load(None)?.tools.restricted_by(&project);
+        }
+        // The same goes for its `[extra-args]`
+        if let Some(extra_args) = project_extra_args {
+            let project: BTreeMap<String, Vec<String>> = extra_args
+                .try_into()
+                .context("invalid project `[extra-args]`")?;
+            config.extra_args = restrict_extra_args(self.load(None)?.extra_args, project);
+        }
+        Ok(config)
+    }
+}
+
+impl ConfigSources {
+    /// The configuration that applies in `cwd` as TOML, preceded by a comment listing where
+    /// it came from
+    pub fn render(&self, cwd: &Path) -> Result<String> {
+        let config = self.load(Some(cwd))?;
+        let mut sources: Vec<String> = self
+            .files(Some(cwd))
+            .iter()
+            .map(|f| f.display().to_string())
+            .collect();
+        if !self.overrides.is_empty() {
+            sources.push("command line".to_string());
+        }
+        let mut text = format!("# Configuration for {}\n", cwd.display());
+        match sources.as_slice() {
+            [] => text.push_str("# Built-in defaults only\n"),
+            sources => {
+                text.push_str("# Read from, lowest precedence first:\n");
+                for source in sources {
+                    text.push_str(&format!("#   {source}\n"));
+                }
+            }
+        }
+        text.push('\n');
+        text.push_str(&toml::to_string(&config)?);
+        Ok(text)
+    }
+}
+
+/// The default user configuration file, under `$XDG_CONFIG_HOME` or `~/.config`
+pub fn user_config_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join(USER_CONFIG_PATH))
+}
+
+/// The flags allowed by both `user` and `project`. A tool that only one of them lists gets
+/// that list, since a tool that is not listed accepts any flag.
+fn restrict_extra_args(
+    mut user: BTreeMap<String, Vec<String>>,
+    project: BTreeMap<String, Vec<String>>,
+) -> BTreeMap<String, Vec<String>> {
+    for (tool, flags) in project {
+        let allowed = match user.remove(&tool) {
+            Some(user) => flags.into_iter().filter(|f| user.contains(f)).collect(),
+            None => flags,
+        };
+        user.insert(tool, allowed);
+    }
+    user
+}
+
+/// Merge `layer` into `base`, recursing into tables that both have
+fn merge(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(layer)) => merge(base, layer),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pipeline() {
+        let config: Config = toml::from_str(indoc::indoc! {r#"
+            [[auto-check.stages]]
+            command = "check"
+
+            [[auto-check.stages]]
+            command = "clippy"
+            args = ["--all-targets", "--", "-D", "warnings"]
+
+            [[auto-check.stages]]
+            command = "fmt"
+            args = ["--check"]
+
+            [[auto-check.stages]]
+            command = "test"
+            touched-modules-only = true
+        "#})
+        .unwrap();
+
+        let stages = &config.auto_check.stages;
+        assert_eq!(stages.len(), 4);
+        assert_eq!(
+            stages[1].label(),
+            "cargo clippy --all-targets -- -D warnings"
+        );
+        assert!(stages[1].uses_json());
+        assert!(!stages[2].uses_json());
+        assert_eq!(
+            stages[3].args_for(["/p/src/parser/lexer.rs", "/p/src/parser/mod.rs"]),
+            ["--", "parser", "parser::lexer"]
+        );
+        assert!(stages[3].args_for(["/p/src/lib.rs"]).is_empty());
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let config: Config = toml::from_str(indoc::indoc! {r#"
+            [timeouts]
+            default = 120
+            cargo_run = 10
+        "#})
+        .unwrap();
+        assert_eq!(config.timeout_for("cargo_run"), Duration::from_secs(10));
+        assert_eq!(config.timeout_for("cargo_build"), Duration::from_secs(120));
+        assert_eq!(Config::default().timeout_for("cargo_run"), DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn test_output_budget() {
+        let config: Config = toml::from_str(indoc::indoc! {r#"
+            [output]
+            max-bytes = 20000
+            max-tokens = 2000
+        "#})
+        .unwrap();
+        assert_eq!(config.output.limit(), 8000);
+        assert_eq!(
+            Config::default().output.limit(),
+            crate::cargo_command::DEFAULT_OUTPUT_BYTES
+        );
+    }
+
+    #[test]
+    fn test_sources() {
+        let dir = std::env::temp_dir().join(format!("config-sources-{}", std::process::id()));
+        let project = dir.join("project");
+        std::fs::create_dir_all(project.join(".symposium")).unwrap();
+        std::fs::create_dir_all(project.join("src")).unwrap();
+        let user = dir.join("user.toml");
+        std::fs::write(
+            &user,
+            indoc::indoc! {r#"
+                [timeouts]
+                default = 60
+                cargo_run = 5
+
+                [tools]
+                disabled = ["cargo_clean"]
+
+                [extra-args]
+                cargo_update = ["--precise", "--dry-run"]
+            "#},
+        )
+        .unwrap();
+        std::fs::write(
+            project.join(PROJECT_CONFIG_PATH),
+            indoc::indoc! {r#"
+                [timeouts]
+                cargo_run = 10
+
+                [tools]
+                disabled = ["cargo_update"]
+
+                [extra-args]
+                cargo_update = ["--dry-run", "--recursive"]
+                cargo_add = ["--dev"]
+            "#},
+        )
+        .unwrap();
+
+        let mut sources = ConfigSources {
+            user: Some(user),
+            overrides: toml::Table::new(),
+        };
+        sources.set(&["auto-check", "max-followups"], 1);
+        let config = sources.load(Some(&project.join("src"))).unwrap();
+        // Tables are merged, everything else is replaced
+        assert_eq!(config.timeout_for("cargo_build"), Duration::from_secs(60));
+        assert_eq!(config.timeout_for("cargo_run"), Duration::from_secs(10));
+        // ...except for the project's `[tools]`, which cannot undo the user's
+        assert_eq!(config.tools.disabled, ["cargo_clean", "cargo_update"]);
+        // ...or allow flags the user left out of `[extra-args]`
+        assert_eq!(config.extra_args["cargo_update"], ["--dry-run"]);
+        assert_eq!(config.extra_args["cargo_add"], ["--dev"]);
+        assert_eq!(config.auto_check.max_followups, 1);
+        assert!(config.auto_check.enabled);
+        assert_eq!(config.auto_check.stages.len(), 1);
+
+        sources.set(&["timeouts", "nope"], "ten");
+        assert!(sources.load(Some(&project)).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tool_policy() {
+        let operator = ToolPolicy {
+            enabled: None,
+            disabled: vec!["cargo_clean".to_string()],
+            confirm: vec!["cargo_add".to_string()],
+        };
+        let project: ToolPolicy = toml::from_str(indoc::indoc! {r#"
+            enabled = ["cargo_check", "cargo_clean", "cargo_add"]
+            confirm = ["cargo_check"]
+        "#})
+        .unwrap();
+        let policy = operator.restricted_by(&project);
+        assert_eq!(
+            policy.enabled.as_deref().unwrap(),
+            ["cargo_check", "cargo_clean", "cargo_add"]
+        );
+        assert_eq!(policy.disabled, ["cargo_clean"]);
+        assert!(policy.requires_confirmation("cargo_add"));
+        assert!(policy.requires_confirmation("cargo_check"));
+        assert!(!policy.requires_confirmation("cargo_clean"));
+
+        let narrower = ToolPolicy {
+            enabled: Some(vec!["cargo_check".to_string()]),
+            ..ToolPolicy::default()
+        };
+        assert_eq!(
+            policy.restricted_by(&narrower).enabled.as_deref().unwrap(),
+            ["cargo_check"]
+        );
+    }
+
+    #[test]
+    fn test_auto_approve() {
+        let config: Config = toml::from_str(indoc::indoc! {r#"
+            [[permissions.auto-approve]]
+            tool = "cargo_add"
+            packages = ["serde"]
+
+            [[permissions.auto-approve]]
+            tool = "cargo_clean"
+            extra-args = true
+        "#})
+        .unwrap();
+        let allowed = |tool: &str, input: serde_json::Value| {
+            config
+                .permissions
+                .auto_approve
+                .iter()
+                .any(|rule| rule.matches(tool, &input))
+        };
+        assert!(allowed(
+            "cargo_add",
+            serde_json::json!({"package": "serde"})
+        ));
+        assert!(!allowed(
+            "cargo_add",
+            serde_json::json!({"package": "tokio"})
+        ));
+        assert!(!allowed(
+            "cargo_add",
+            serde_json::json!({"package": "serde", "extra_args": ["--git", "https://example.com"]})
+        ));
+        assert!(allowed(
+            "cargo_clean",
+            serde_json::json!({"extra_args": ["--doc"]})
+        ));
+        assert!(!allowed("cargo_update", serde_json::json!({})));
+    }
+
+    #[test]
+    fn test_extra_args() {
+        let config: Config = toml::from_str(indoc::indoc! {r#"
+            [extra-args]
+            cargo_update = ["--precise", "--dry-run"]
+        "#})
+        .unwrap();
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        assert!(
+            config
+                .check_extra_args("cargo_update", &args(&["--precise", "1.0.1"]))
+                .is_ok()
+        );
+        assert!(
+            config
+                .check_extra_args("cargo_update", &args(&["--precise=1.0.1"]))
+                .is_ok()
+        );
+        assert!(
+            config
+                .check_extra_args("cargo_update", &args(&["--recursive"]))
+                .is_err()
+        );
+        assert!(
+            config
+                .check_extra_args("cargo_add", &args(&["--dev"]))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_default_is_cargo_check() {
+        let config: Config = toml::from_str("").unwrap();
+        assert_eq!(config.auto_check.stages.len(), 1);
+        assert_eq!(config.auto_check.stages[0].label(), "cargo check");
+    }
+}
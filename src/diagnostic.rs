@@ -0,0 +1,299 @@
+use std::collections::BTreeSet;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Severity of a compiler diagnostic, as reported by rustc
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiagnosticLevel {
+    #[serde(rename = "error: internal compiler error")]
+    Ice,
+    Error,
+    Warning,
+    Note,
+    Help,
+    FailureNote,
+    #[serde(other)]
+    Unknown,
+}
+
+/// How confident rustc is that a suggestion can be applied without review
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub enum Applicability {
+    MachineApplicable,
+    MaybeIncorrect,
+    HasPlaceholders,
+    #[serde(other)]
+    Unspecified,
+}
+
+/// A region of source code a diagnostic refers to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct DiagnosticSpan {
+    pub file: String,
+    pub line_start: usize,
+    pub line_end: usize,
+    pub column_start: usize,
+    pub column_end: usize,
+    pub byte_start: usize,
+    pub byte_end: usize,
+    /// Primary spans point at the problem itself; secondary spans add context
+    pub is_primary: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+}
+
+/// A replacement rustc proposes for a span of source code
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Suggestion {
+    pub message: String,
+    pub span: DiagnosticSpan,
+    pub replacement: String,
+    pub applicability: Applicability,
+}
+
+/// A note or help message attached to a diagnostic
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct DiagnosticChild {
+    pub level: DiagnosticLevel,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub spans: Vec<DiagnosticSpan>,
+}
+
+/// A structured compiler diagnostic extracted from a `compiler-message`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Diagnostic {
+    pub level: DiagnosticLevel,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub spans: Vec<DiagnosticSpan>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub children: Vec<DiagnosticChild>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub suggestions: Vec<Suggestion>,
+}
+
+/// The subset of rustc's JSON diagnostic format that we consume
+#[derive(Deserialize)]
+struct RawDiagnostic {
+    message: String,
+    code: Option<RawCode>,
+    level: DiagnosticLevel,
+    #[serde(default)]
+    spans: Vec<RawSpan>,
+    #[serde(default)]
+    children: Vec<RawDiagnostic>,
+}
+
+#[derive(Deserialize)]
+struct RawCode {
+    code: String,
+}
+
+#[derive(Deserialize)]
+struct RawSpan {
+    file_name: String,
+    byte_start: usize,
+    byte_end: usize,
+    line_start: usize,
+    line_end: usize,
+    column_start: usize,
+    column_end: usize,
+    is_primary: bool,
+    label: Option<String>,
+    suggested_replacement: Option<String>,
+    suggestion_applicability: Option<Applicability>,
+}
+
+impl RawSpan {
+    fn to_span(&self) -> DiagnosticSpan {
+        DiagnosticSpan {
+            file: self.file_name.clone(),
+            line_start: self.line_start,
+            line_end: self.line_end,
+            column_start: self.column_start,
+            column_end: self.column_end,
+            byte_start: self.byte_start,
+            byte_end: self.byte_end,
+            is_primary: self.is_primary,
+            label: self.label.clone(),
+        }
+    }
+
+    fn to_suggestion(&self, message: &str) -> Option<Suggestion> {
+        Some(Suggestion {
+            message: message.to_string(),
+            span: self.to_span(),
+            replacement: self.suggested_replacement.clone()?,
+            applicability: self
+                .suggestion_applicability
+                .unwrap_or(Applicability::Unspecified),
+        })
+    }
+}
+
+impl Diagnostic {
+    /// Parse the `message` field of a cargo `compiler-message` into a [`Diagnostic`]
+    pub fn from_compiler_message(message: &serde_json::Value) -> Option<Diagnostic> {
+        let raw = RawDiagnostic::deserialize(message).ok()?;
+
+        let mut suggestions: Vec<Suggestion> = raw
+            .spans
+            .iter()
+            .filter_map(|s| s.to_suggestion(&raw.message))
+            .collect();
+        suggestions.extend(raw.children.iter().flat_map(|child| {
+            child
+                .spans
+                .iter()
+                .filter_map(|s| s.to_suggestion(&child.message))
+        }));
+
+        Some(Diagnostic {
+            level: raw.level,
+            code: raw.code.map(|c| c.code),
+            message: raw.message,
+            spans: raw.spans.iter().map(RawSpan::to_span).collect(),
+            children: raw
+                .children
+                .iter()
+                .map(|child| DiagnosticChild {
+                    level: child.level,
+                    message: child.message.clone(),
+                    spans: child
+                        .spans
+                        .iter()
+                        .filter(|s| s.suggested_replacement.is_none())
+                        .map(RawSpan::to_span)
+                        .collect(),
+                })
+                .collect(),
+            suggestions,
+        })
+    }
+}
+
+impl Diagnostic {
+    /// Identifies the problem independently of where it currently sits in the file, so the
+    /// same error reported before and after unrelated edits compares equal
+    pub(crate) fn key(&self) -> (DiagnosticLevel, Option<&str>, &str, Option<&str>) {
+        let file = self
+            .spans
+            .iter()
+            .find(|s| s.is_primary)
+            .map(|s| s.file.as_str());
+        (self.level, self.code.as_deref(), &self.message, file)
+    }
+}
+
+/// Whether two runs reported the same set of problems, ignoring order and source positions
+pub(crate) fn same_diagnostics(a: &[Diagnostic], b: &[Diagnostic]) -> bool {
+    let a: BTreeSet<_> = a.iter().map(Diagnostic::key).collect();
+    let b: BTreeSet<_> = b.iter().map(Diagnostic::key).collect();
+    a == b
+}
+
+#[cfg(test)]
+impl Diagnostic {
+    /// A diagnostic for tests, without a code or spans
+    pub(crate) fn for_test(level: DiagnosticLevel, message: &str) -> Self {
+        Diagnostic {
+            level,
+            code: None,
+            message: message.to_string(),
+            spans: vec![],
+            children: vec![],
+            suggestions: vec![],
+        }
+    }
+
+    pub(crate) fn with_code(self, code: &str) -> Self {
+        Diagnostic {
+            code: Some(code.to_string()),
+            ..self
+        }
+    }
+
+    /// Add a primary span at the start of `line` in `file`
+    pub(crate) fn at(mut self, file: &str, line: usize) -> Self {
+        self.spans.push(DiagnosticSpan {
+            file: file.to_string(),
+            line_start: line,
+            line_end: line,
+            column_start: 1,
+            column_end: 2,
+            byte_start: 0,
+            byte_end: 1,
+            is_primary: true,
+            label: None,
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_unused_variable() {
+        let message = serde_json::json!({
+            "$message_type": "diagnostic",
+            "message": "unused variable: `x`",
+            "code": { "code": "unused_variables", "explanation": null },
+            "level": "warning",
+            "spans": [{
+                "file_name": "src/main.rs",
+                "byte_start": 16, "byte_end": 17,
+                "line_start": 2, "line_end": 2,
+                "column_start": 9, "column_end": 10,
+                "is_primary": true,
+                "text": [],
+                "label": null,
+                "suggested_replacement": null,
+                "suggestion_applicability": null,
+                "expansion": null
+            }],
+            "children": [{
+                "message": "if this is intentional, prefix it with an underscore",
+                "code": null,
+                "level": "help",
+                "spans": [{
+                    "file_name": "src/main.rs",
+                    "byte_start": 16, "byte_end": 17,
+                    "line_start": 2, "line_end": 2,
+                    "column_start": 9, "column_end": 10,
+                    "is_primary": true,
+                    "text": [],
+                    "label": null,
+                    "suggested_replacement": "_x",
+                    "suggestion_applicability": "MachineApplicable",
+                    "expansion": null
+                }],
+                "children": [],
+                "rendered": null
+            }],
+            "rendered": "warning: unused variable: `x`\n"
+        });
+
+        let diagnostic = Diagnostic::from_compiler_message(&message).unwrap();
+        assert_eq!(diagnostic.level, DiagnosticLevel::Warning);
+        assert_eq!(diagnostic.code.as_deref(), Some("unused_variables"));
+        assert_eq!(diagnostic.spans.len(), 1);
+        assert_eq!(diagnostic.spans[0].line_start, 2);
+        assert_eq!(diagnostic.children.len(), 1);
+        assert!(diagnostic.children[0].spans.is_empty());
+        assert_eq!(diagnostic.suggestions.len(), 1);
+        assert_eq!(diagnostic.suggestions[0].replacement, "_x");
+        assert_eq!(
+            diagnostic.suggestions[0].applicability,
+            Applicability::MachineApplicable
+        );
+    }
+}
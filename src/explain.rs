@@ -0,0 +1,150 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::cargo_command::CargoCommandJsonResult;
+use crate::toolchain;
+
+/// Explanations already fetched, by rustc version and error code. The text never changes for a
+/// given rustc, so it is shared by all sessions.
+static EXPLANATIONS: Mutex<BTreeMap<(String, String), String>> = Mutex::new(BTreeMap::new());
+
+/// Result of `rustc_explain`
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RustcExplainResult {
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rustc_version: Option<String>,
+    /// The full explanation, in markdown, with erroneous and fixed examples
+    pub explanation: String,
+}
+
+/// `rustc --explain <code>`, from the cache if this rustc was asked before
+pub async fn explain(
+    code: &str,
+    toolchain: Option<&str>,
+    cwd: Option<&str>,
+) -> anyhow::Result<RustcExplainResult> {
+    let rustc_version = toolchain::rustc_version(toolchain, cwd).await;
+    let explanation = explanation(code, rustc_version.as_deref(), toolchain, cwd).await?;
+    Ok(RustcExplainResult {
+        code: code.to_string(),
+        rustc_version,
+        explanation,
+    })
+}
+
+/// Short explanations of the distinct error codes among `result`'s diagnostics, by code.
+/// Codes rustc cannot explain are left out.
+pub async fn summaries(
+    result: &CargoCommandJsonResult,
+    toolchain: Option<&str>,
+    cwd: Option<&str>,
+) -> BTreeMap<String, String> {
+    let codes: BTreeSet<&str> = result
+        .diagnostics
+        .iter()
+        .filter_map(|d| d.code.as_deref())
+        .filter(|code| is_error_code(code))
+        .collect();
+    let mut summaries = BTreeMap::new();
+    if codes.is_empty() {
+        return summaries;
+    }
+    let rustc_version = toolchain::rustc_version(toolchain, cwd).await;
+    for code in codes {
+        match explanation(code, rustc_version.as_deref(), toolchain, cwd).await {
+            Ok(text) => {
+                summaries.insert(code.to_string(), summary(&text));
+            }
+            Err(err) => tracing::debug!(?err, code, "no explanation"),
+        }
+    }
+    summaries
+}
+
+async fn explanation(
+    code: &str,
+    rustc_version: Option<&str>,
+    toolchain: Option<&str>,
+    cwd: Option<&str>,
+) -> anyhow::Result<String> {
+    // Without a version there is no telling which rustc answered, so nothing is cached
+    let key = rustc_version.map(|version| (version.to_string(), code.to_string()));
+    if let Some(key) = &key {
+        let cache = EXPLANATIONS.lock().expect("not poisoned");
+        if let Some(text) = cache.get(key) {
+            return Ok(text.clone());
+        }
+    }
+
+    let mut cmd = Command::new("rustc");
+    if let Some(toolchain) = toolchain {
+        cmd.arg(format!("+{toolchain}"));
+    }
+    cmd.args(["--explain", code]);
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    let output = cmd.output().await?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "rustc cannot explain `{code}`: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let text = String::from_utf8_lossy(&output.stdout).into_owned();
+
+    if let Some(key) = key {
+        let mut cache = EXPLANATIONS.lock().expect("not poisoned");
+        cache.insert(key, text.clone());
+    }
+    Ok(text)
+}
+
+/// Whether `code` looks like a rustc error code such as `E0425`, rather than a lint name
+fn is_error_code(code: &str) -> bool {
+    code.strip_prefix('E')
+        .is_some_and(|digits| digits.len() == 4 && digits.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// The first paragraph of an explanation, on one line, skipping notes like
+/// `#### Note: this error code is no longer emitted by the compiler.`
+fn summary(explanation: &str) -> String {
+    explanation
+        .split("\n\n")
+        .map(str::trim)
+        .find(|paragraph| !paragraph.is_empty() && !paragraph.starts_with('#'))
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        assert!(is_error_code("E0425"));
+        assert!(!is_error_code("unused_variables"));
+        assert!(!is_error_code("E042"));
+
+        let explanation = indoc::indoc! {"
+            #### Note: this error code is no longer emitted by the compiler.
+
+            This error suggests that the expression arm corresponding to the noted pattern
+            will never be reached.
+
+            Erroneous code example:
+        "};
+        assert_eq!(
+            summary(explanation),
+            "This error suggests that the expression arm corresponding to the noted pattern will never be reached."
+        );
+    }
+}
@@ -0,0 +1,444 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Flags cargo accepts for every subcommand that read configuration or run other programs, so
+/// they could make cargo execute anything
+const FORBIDDEN: &[&str] = &["--config", "-Z", "-C", "--lockfile-path"];
+
+/// What a flag is followed by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Takes {
+    Nothing,
+    Value,
+    /// A path, which has to stay inside the workspace
+    Path,
+}
+
+struct Flag {
+    long: &'static str,
+    short: Option<&'static str>,
+    takes: Takes,
+}
+
+const fn flag(long: &'static str, takes: Takes) -> Flag {
+    Flag {
+        long,
+        short: None,
+        takes,
+    }
+}
+
+const fn short(long: &'static str, short: &'static str, takes: Takes) -> Flag {
+    Flag {
+        long,
+        short: Some(short),
+        takes,
+    }
+}
+
+/// Flags of every subcommand below
+const COMMON: &[Flag] = &[
+    flag("--manifest-path", Takes::Path),
+    flag("--offline", Takes::Nothing),
+    flag("--locked", Takes::Nothing),
+    flag("--frozen", Takes::Nothing),
+    short("--quiet", "-q", Takes::Nothing),
+    short("--verbose", "-v", Takes::Nothing),
+];
+
+const ADD: &[Flag] = &[
+    flag("--dev", Takes::Nothing),
+    flag("--build", Takes::Nothing),
+    flag("--target", Takes::Value),
+    short("--features", "-F", Takes::Value),
+    flag("--no-default-features", Takes::Nothing),
+    flag("--default-features", Takes::Nothing),
+    flag("--optional", Takes::Nothing),
+    flag("--no-optional", Takes::Nothing),
+    flag("--rename", Takes::Value),
+    short("--package", "-p", Takes::Value),
+    short("--dry-run", "-n", Takes::Nothing),
+    flag("--path", Takes::Path),
+    flag("--git", Takes::Value),
+    flag("--branch", Takes::Value),
+    flag("--tag", Takes::Value),
+    flag("--rev", Takes::Value),
+    flag("--registry", Takes::Value),
+    flag("--ignore-rust-version", Takes::Nothing),
+];
+
+const CLEAN: &[Flag] = &[
+    short("--package", "-p", Takes::Value),
+    short("--release", "-r", Takes::Nothing),
+    flag("--profile", Takes::Value),
+    flag("--target", Takes::Value),
+    // No `--target-dir`: clean deletes whatever directory it names, which could be the workspace
+    // itself
+    flag("--doc", Takes::Nothing),
+    short("--dry-run", "-n", Takes::Nothing),
+];
+
+const REMOVE: &[Flag] = &[
+    flag("--dev", Takes::Nothing),
+    flag("--build", Takes::Nothing),
+    flag("--target", Takes::Value),
+    short("--package", "-p", Takes::Value),
+    short("--dry-run", "-n", Takes::Nothing),
+];
+
+const UPDATE: &[Flag] = &[
+    short("--package", "-p", Takes::Value),
+    short("--workspace", "-w", Takes::Nothing),
+    flag("--recursive", Takes::Nothing),
+    flag("--precise", Takes::Value),
+    short("--dry-run", "-n", Takes::Nothing),
+    flag("--ignore-rust-version", Takes::Nothing),
+];
+
+/// Subcommands an `auto-check` stage may run; the others change the workspace or publish it
+pub(crate) const STAGE_COMMANDS: &[&str] = &["check", "clippy", "fmt", "test", "build"];
+
+/// Flags of the `args` of an `auto-check` stage running one of [`STAGE_COMMANDS`]. What comes
+/// after `--` goes to the compiler, rustfmt or the tests, so it is not checked.
+const AUTO_CHECK: &[Flag] = &[
+    short("--package", "-p", Takes::Value),
+    flag("--workspace", Takes::Nothing),
+    flag("--all", Takes::Nothing),
+    flag("--exclude", Takes::Value),
+    flag("--lib", Takes::Nothing),
+    flag("--bins", Takes::Nothing),
+    flag("--bin", Takes::Value),
+    flag("--examples", Takes::Nothing),
+    flag("--example", Takes::Value),
+    flag("--tests", Takes::Nothing),
+    flag("--test", Takes::Value),
+    flag("--benches", Takes::Nothing),
+    flag("--bench", Takes::Value),
+    flag("--all-targets", Takes::Nothing),
+    flag("--doc", Takes::Nothing),
+    short("--features", "-F", Takes::Value),
+    flag("--all-features", Takes::Nothing),
+    flag("--no-default-features", Takes::Nothing),
+    short("--release", "-r", Takes::Nothing),
+    flag("--profile", Takes::Value),
+    flag("--target", Takes::Value),
+    short("--jobs", "-j", Takes::Value),
+    flag("--keep-going", Takes::Nothing),
+    flag("--no-run", Takes::Nothing),
+    flag("--no-fail-fast", Takes::Nothing),
+    flag("--no-deps", Takes::Nothing),
+    flag("--check", Takes::Nothing),
+];
+
+/// The flags `tool` accepts in `extra_args`, and whether it takes positional arguments such as
+/// package names
+fn flags_of(tool: &str) -> Option<(&'static [Flag], bool)> {
+    match tool {
+        "cargo_add" => Some((ADD, true)),
+        "cargo_clean" => Some((CLEAN, false)),
+        "cargo_remove" => Some((REMOVE, true)),
+        "cargo_update" => Some((UPDATE, true)),
+        "auto_check" => Some((AUTO_CHECK, true)),
+        _ => None,
+    }
+}
+
+/// Why an argument was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Rejection {
+    /// A flag that could make cargo read configuration or run other programs, like `--config`
+    Forbidden,
+    /// Not one of the flags the tool accepts
+    UnknownFlag,
+    /// The tool takes no positional arguments
+    UnexpectedArgument,
+    /// A flag that needs a value came last
+    MissingValue,
+    /// A flag's value starts with `-`, so cargo could read it as a flag of its own
+    FlagAsValue,
+    /// A path that points outside the workspace
+    OutsideWorkspace,
+    /// Left out of the tool's `extra-args` list in the configuration
+    NotConfigured,
+}
+
+/// An argument of a tool call that was not passed on to cargo. Returned to the agent as the
+/// `data` of an invalid params error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct ExtraArgsError {
+    pub tool: String,
+    pub arg: String,
+    pub rejection: Rejection,
+    /// The flags the tool accepts
+    pub allowed: Vec<String>,
+}
+
+impl ExtraArgsError {
+    pub(crate) fn new(tool: &str, arg: &str, rejection: Rejection, allowed: Vec<String>) -> Self {
+        Self {
+            tool: tool.to_string(),
+            arg: arg.to_string(),
+            rejection,
+            allowed,
+        }
+    }
+}
+
+impl fmt::Display for ExtraArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (tool, arg) = (&self.tool, &self.arg);
+        match self.rejection {
+            Rejection::Forbidden => write!(f, "`{arg}` is never passed on by `{tool}`"),
+            Rejection::UnknownFlag => write!(f, "`{tool}` does not accept `{arg}`"),
+            Rejection::UnexpectedArgument => {
+                write!(f, "`{tool}` takes no positional arguments, got `{arg}`")
+            }
+            Rejection::MissingValue => write!(f, "`{arg}` needs a value"),
+            Rejection::FlagAsValue => write!(f, "`{arg}` has a value that starts with `-`"),
+            Rejection::OutsideWorkspace => {
+                write!(f, "`{arg}` points outside the workspace")
+            }
+            Rejection::NotConfigured => {
+                write!(f, "`{arg}` is not allowed in the extra args of `{tool}`")
+            }
+        }?;
+        if !self.allowed.is_empty() {
+            write!(f, "; allowed flags: {}", self.allowed.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ExtraArgsError {}
+
+impl From<ExtraArgsError> for sacp::Error {
+    fn from(error: ExtraArgsError) -> Self {
+        let data = serde_json::to_value(&error).expect("serializable");
+        sacp::Error::new(sacp::ErrorCode::InvalidParams.into(), error.to_string()).data(data)
+    }
+}
+
+/// Check the arguments `tool` is about to pass to cargo against the flags its subcommand
+/// accepts. Returns the paths among them, which [`check_paths`] has to confirm are inside the
+/// workspace. Tools without a list of flags accept anything.
+pub fn check<'a>(tool: &str, args: &[&'a str]) -> Result<Vec<(&'a str, &'a str)>, ExtraArgsError> {
+    let Some((flags, positional)) = flags_of(tool) else {
+        return Ok(Vec::new());
+    };
+    let all = || COMMON.iter().chain(flags);
+    let allowed = || {
+        all()
+            .flat_map(|f| std::iter::once(f.long).chain(f.short))
+            .map(str::to_string)
+            .collect()
+    };
+    let reject = |arg: &str, rejection| Err(ExtraArgsError::new(tool, arg, rejection, allowed()));
+
+    let mut paths = Vec::new();
+    let mut args = args.iter().copied();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            if let Some(rest) = args.next().filter(|_| !positional) {
+                return reject(rest, Rejection::UnexpectedArgument);
+            }
+            break;
+        }
+        if !arg.starts_with('-') || arg == "-" {
+            if !positional {
+                return reject(arg, Rejection::UnexpectedArgument);
+            }
+            continue;
+        }
+
+        // `--flag=value`, `--flag value`, `-xvalue` or `-x value`
+        let (name, attached) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => (name, Some(value)),
+            _ if !arg.starts_with("--") && arg.len() > 2 => {
+                let split = arg.char_indices().nth(2).map_or(arg.len(), |(i, _)| i);
+                (&arg[..split], Some(&arg[split..]))
+            }
+            _ => (arg, None),
+        };
+        if FORBIDDEN.contains(&name) {
+            return reject(arg, Rejection::Forbidden);
+        }
+        let Some(flag) = all().find(|f| f.long == name || f.short == Some(name)) else {
+            return reject(arg, Rejection::UnknownFlag);
+        };
+        let value = match (flag.takes, attached) {
+            (Takes::Nothing, None) => continue,
+            (Takes::Nothing, Some(_)) => return reject(arg, Rejection::UnknownFlag),
+            (_, Some(value)) => value,
+            (_, None) => match args.next() {
+                Some(value) => value,
+                None => return reject(arg, Rejection::MissingValue),
+            },
+        };
+        // Flags like `-p` take an optional value, so cargo parses `-p --config=..` as two flags
+        if value.starts_with('-') {
+            return reject(arg, Rejection::FlagAsValue);
+        }
+        if flag.takes == Takes::Path {
+            paths.push((arg, value));
+        }
+    }
+    Ok(paths)
+}
+
+/// Check that `paths`, as returned by [`check`] and relative to `cwd`, are inside `root`
+pub fn check_paths(
+    tool: &str,
+    paths: &[(&str, &str)],
+    cwd: &Path,
+    root: &Path,
+) -> Result<(), ExtraArgsError> {
+    let root = resolve(root);
+    for (arg, path) in paths {
+        if !resolve(&cwd.join(path)).starts_with(&root) {
+            return Err(ExtraArgsError::new(
+                tool,
+                arg,
+                Rejection::OutsideWorkspace,
+                Vec::new(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `path` made absolute with `..` and symbolic links resolved, as far as it exists
+fn resolve(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut resolved = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::CurDir => {}
+            component => {
+                resolved.push(component);
+                if let Ok(real) = resolved.canonicalize() {
+                    resolved = real;
+                }
+            }
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert_eq!(
+            check(
+                "cargo_add",
+                &["serde", "-F", "derive", "--features=std", "-Fa"]
+            ),
+            Ok(vec![])
+        );
+        assert_eq!(
+            check("cargo_update", &["-p", "serde", "--precise", "1.0.1"]),
+            Ok(vec![])
+        );
+
+        let rejection = |tool, args: &[&str]| check(tool, args).unwrap_err().rejection;
+        assert_eq!(
+            rejection("cargo_update", &["--config", "build.rustc-wrapper='sh'"]),
+            Rejection::Forbidden
+        );
+        assert_eq!(
+            rejection("cargo_add", &["serde", "--config=x"]),
+            Rejection::Forbidden
+        );
+        assert_eq!(
+            rejection("cargo_clean", &["-Zunstable-options"]),
+            Rejection::Forbidden
+        );
+        assert_eq!(
+            rejection("cargo_remove", &["serde", "--git", "x"]),
+            Rejection::UnknownFlag
+        );
+        assert_eq!(
+            rejection("cargo_clean", &["serde"]),
+            Rejection::UnexpectedArgument
+        );
+        assert_eq!(
+            rejection("cargo_update", &["--precise"]),
+            Rejection::MissingValue
+        );
+        // Cargo would apply the `--config` instead of taking it as the package
+        for tool in ["cargo_add", "cargo_clean", "cargo_remove", "cargo_update"] {
+            assert_eq!(
+                rejection(tool, &["-p", "--config=build.target-dir=\"/elsewhere\""]),
+                Rejection::FlagAsValue
+            );
+        }
+        assert_eq!(
+            rejection("cargo_clean", &["--package", "--config", "x", "-p", "proj"]),
+            Rejection::FlagAsValue
+        );
+        assert_eq!(
+            rejection("cargo_update", &["--precise=-1"]),
+            Rejection::FlagAsValue
+        );
+        assert_eq!(
+            rejection("cargo_clean", &["--doc=yes"]),
+            Rejection::UnknownFlag
+        );
+        // Clean deletes the target directory, so it must not name the workspace or its sources
+        assert_eq!(
+            rejection("cargo_clean", &["--target-dir", "."]),
+            Rejection::UnknownFlag
+        );
+        assert_eq!(
+            rejection("cargo_clean", &["--target-dir", "src"]),
+            Rejection::UnknownFlag
+        );
+
+        // Stages of the automatic check
+        assert_eq!(
+            check(
+                "auto_check",
+                &["--all-targets", "--", "-D", "warnings", "--config", "x"]
+            ),
+            Ok(vec![])
+        );
+        assert_eq!(
+            rejection("auto_check", &["--config", "x"]),
+            Rejection::Forbidden
+        );
+        assert_eq!(rejection("auto_check", &["--fix"]), Rejection::UnknownFlag);
+
+        // Tools without a list accept anything
+        assert_eq!(check("cargo_run", &["--config", "x"]), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_check_paths() {
+        let root = std::env::temp_dir().join(format!("extra-args-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("crates/core")).unwrap();
+        let cwd = root.join("crates/core");
+
+        let args = ["--manifest-path", "../../Cargo.toml", "--path=../core"];
+        let paths = check("cargo_add", &args).unwrap();
+        assert_eq!(paths.len(), 2);
+        assert!(check_paths("cargo_add", &paths, &cwd, &root).is_ok());
+
+        let paths = check("cargo_clean", &["--manifest-path", "../../../Cargo.toml"]).unwrap();
+        let error = check_paths("cargo_clean", &paths, &cwd, &root).unwrap_err();
+        assert_eq!(error.rejection, Rejection::OutsideWorkspace);
+        assert_eq!(error.arg, "--manifest-path");
+        let paths = check("cargo_add", &["--path", "/etc"]).unwrap();
+        assert!(check_paths("cargo_add", &paths, &cwd, &root).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::cargo_command::{
+    CargoCommandJsonResult, CommandStatus, ToolResponse, Truncation, keep_within,
+};
+
+/// A file rustfmt changed, or would change in check mode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct FormattedFile {
+    pub file: String,
+    /// Unified diff of the changes rustfmt would make; only reported in check mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+/// Result of `cargo fmt`, with rustfmt's output parsed into the affected files
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CargoFmtResult {
+    #[serde(flatten)]
+    pub command: CargoCommandJsonResult,
+    pub files: Vec<FormattedFile>,
+    /// Set when rustfmt failed rather than finding differences, e.g. because `rustfmt.toml`
+    /// or a source file could not be parsed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl CargoFmtResult {
+    /// Parse the diffs `cargo fmt --check` prints
+    pub fn from_check(command: CargoCommandJsonResult) -> Self {
+        Self::new(command, parse_check_output)
+    }
+
+    /// Parse the file names `cargo fmt -- -l` prints
+    pub fn from_write(command: CargoCommandJsonResult) -> Self {
+        Self::new(command, |stdout| {
+            stdout
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| FormattedFile {
+                    file: line.trim().to_string(),
+                    diff: None,
+                })
+                .collect()
+        })
+    }
+
+    fn new(
+        mut command: CargoCommandJsonResult,
+        parse: impl FnOnce(&str) -> Vec<FormattedFile>,
+    ) -> Self {
+        let files = parse(&command.stdout);
+        if !files.is_empty() {
+            command.stdout.clear();
+        }
+        // `--check` exits with 1 when it finds differences, so only a failure that left no
+        // diffs behind means rustfmt itself went wrong
+        let error = (command.status == CommandStatus::Completed
+            && command.exit_code != Some(0)
+            && files.is_empty())
+        .then(|| command.stderr.clone());
+        CargoFmtResult {
+            command,
+            files,
+            error,
+        }
+    }
+}
+
+impl ToolResponse for CargoFmtResult {
+    fn command_mut(&mut self) -> &mut CargoCommandJsonResult {
+        &mut self.command
+    }
+
+    fn trim_parts(&mut self, limit: usize, truncation: &mut Truncation) {
+        truncation.elided_entries += keep_within(&mut self.files, limit);
+    }
+}
+
+/// Turn rustfmt's check output, a series of `Diff in <file>:<line>:` headers each followed by
+/// `-`, `+` and ` ` lines, into one unified diff per file
+fn parse_check_output(stdout: &str) -> Vec<FormattedFile> {
+    struct Hunk<'a> {
+        line: usize,
+        lines: Vec<&'a str>,
+    }
+
+    let mut files: Vec<(String, Vec<Hunk>)> = Vec::new();
+    for line in stdout.lines() {
+        if let Some((file, start)) = parse_diff_header(line) {
+            let hunk = Hunk {
+                line: start,
+                lines: Vec::new(),
+            };
+            match files.last_mut() {
+                Some((last, hunks)) if *last == file => hunks.push(hunk),
+                _ => files.push((file, vec![hunk])),
+            }
+        } else if let Some((_, hunks)) = files.last_mut()
+            && let Some(hunk) = hunks.last_mut()
+            && (line.is_empty() || line.starts_with(['-', '+', ' ']))
+        {
+            // Blank context lines can lose their leading space along the way
+            hunk.lines.push(if line.is_empty() { " " } else { line });
+        }
+    }
+
+    files
+        .into_iter()
+        .map(|(file, hunks)| {
+            let mut diff = format!("--- {file}\n+++ {file}\n");
+            // How far the formatted file has shifted relative to the original so far
+            let mut offset: isize = 0;
+            for hunk in hunks {
+                let old = hunk.lines.iter().filter(|l| !l.starts_with('+')).count();
+                let new = hunk.lines.iter().filter(|l| !l.starts_with('-')).count();
+                let new_start = hunk.line as isize + offset;
+                diff.push_str(&format!("@@ -{},{old} +{new_start},{new} @@\n", hunk.line));
+                for line in hunk.lines {
+                    diff.push_str(line);
+                    diff.push('\n');
+                }
+                offset += new as isize - old as isize;
+            }
+            FormattedFile {
+                file,
+                diff: Some(diff),
+            }
+        })
+        .collect()
+}
+
+/// Parse `Diff in src/lib.rs:12:` or, from newer rustfmt versions, `Diff in src/lib.rs at line 12:`
+fn parse_diff_header(line: &str) -> Option<(String, usize)> {
+    let header = line.strip_prefix("Diff in ")?.strip_suffix(':')?;
+    let (file, start) = header
+        .rsplit_once(" at line ")
+        .or_else(|| header.rsplit_once(':'))?;
+    Some((file.to_string(), start.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_check_output() {
+        let stdout = indoc::indoc! {"
+            Diff in /p/src/lib.rs:1:
+            -fn  f( ){}
+            +fn f() {}
+
+             #[cfg(test)]
+             mod tests {
+            Diff in /p/src/lib.rs:5:
+            -    #[test] fn a() {}
+            +    #[test]
+            +    fn a() {}
+             }
+            Diff in /p/src/main.rs at line 2:
+            -  x
+            +    x
+        "};
+        let files = parse_check_output(stdout);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].file, "/p/src/lib.rs");
+        assert_eq!(
+            files[0].diff.as_deref().unwrap(),
+            "--- /p/src/lib.rs\n+++ /p/src/lib.rs\n\
+             @@ -1,4 +1,4 @@\n-fn  f( ){}\n+fn f() {}\n \n #[cfg(test)]\n mod tests {\n\
+             @@ -5,2 +5,3 @@\n-    #[test] fn a() {}\n+    #[test]\n+    fn a() {}\n }\n"
+        );
+        assert_eq!(files[1].file, "/p/src/main.rs");
+    }
+}
@@ -1,68 +1,252 @@
+mod auto_check;
+pub mod baseline;
+mod cancellation;
 mod cargo_command;
 pub mod cargo_mcp;
+pub mod config;
+pub mod diagnostic;
+pub mod explain;
+pub mod extra_args;
+pub mod fmt;
+pub mod libtest;
+pub mod metadata;
+mod permission;
+mod progress;
+pub mod results;
+pub mod run;
+pub mod selection;
+pub mod session;
+pub mod suggestions;
+pub mod toolchain;
+pub mod tree;
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use anyhow::Result;
 pub use cargo_mcp::build_mcp_server;
-use sacp::component::Component;
+pub use config::{ConfigSources, ToolPolicy};
+use sacp::component::{Component, DynComponent};
 use sacp::link::{ConductorToProxy, ProxyToConductor};
+use sacp::mcp::{McpClientToServer, McpServerToClient};
 use sacp::schema::{
-    ContentChunk, PromptRequest, SessionNotification, SessionUpdate, TextContent, ToolCallStatus,
+    CancelNotification, NewSessionRequest, NewSessionResponse, PromptRequest, SessionNotification,
+    SessionUpdate, ToolCallStatus,
 };
-use sacp::{AgentPeer, ClientPeer, on_receive_request};
-use tokio::sync::RwLock;
+use sacp::{AgentPeer, ClientPeer, JrConnectionCx, JrRequestCx, on_receive_request};
+pub use session::{SessionState, Sessions};
 
-pub struct CargoProxy;
+pub struct CargoProxy {
+    config: ConfigSources,
+    tool_policy: ToolPolicy,
+    progress_updates: bool,
+}
+
+impl CargoProxy {
+    pub fn new() -> Self {
+        Self {
+            config: ConfigSources::default(),
+            tool_policy: ToolPolicy::default(),
+            progress_updates: true,
+        }
+    }
+
+    /// Where the configuration is read from; by default the user and project files
+    pub fn config_sources(mut self, sources: ConfigSources) -> Self {
+        self.config = sources;
+        self
+    }
+
+    /// Which tools are offered, and which ask the user before they run. The configuration
+    /// files can restrict this further, but not loosen it.
+    pub fn tool_policy(mut self, policy: ToolPolicy) -> Self {
+        self.tool_policy = policy;
+        self
+    }
+
+    /// How many times in a row the agent is prompted to fix a failing automatic check,
+    /// overriding `auto-check.max-followups` from the configuration files
+    pub fn max_auto_check_followups(mut self, max: usize) -> Self {
+        self.config
+            .set(&["auto-check", "max-followups"], max as i64);
+        self
+    }
+
+    /// Whether the progress of long cargo commands is shown to the client as tool calls
+    pub fn progress_updates(mut self, enabled: bool) -> Self {
+        self.progress_updates = enabled;
+        self
+    }
+}
+
+impl Default for CargoProxy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create the state of a new session, with its own MCP server so tools resolve the cwd of
+/// the session that called them, and forward the request to the agent.
+fn start_session(
+    new_session_req: NewSessionRequest,
+    req_cx: JrRequestCx<NewSessionResponse>,
+    conn_cx: JrConnectionCx<ProxyToConductor>,
+    sessions: Sessions,
+    config: Arc<ConfigSources>,
+    tool_policy: &ToolPolicy,
+    progress_updates: bool,
+) -> Result<(), sacp::Error> {
+    let session = Arc::new(
+        SessionState::new(Some(new_session_req.cwd.display().to_string()))
+            .with_config_sources(config)
+            .with_progress_updates(progress_updates),
+    );
+    conn_cx
+        .build_session_from(new_session_req)
+        .with_mcp_server(build_mcp_server(session.clone(), tool_policy.clone())?)?
+        .on_proxy_session_start(req_cx, {
+            let conn_cx = conn_cx.clone();
+            async move |session_id| {
+                session.set_session_id(session_id.clone());
+                sessions.insert(session_id, session.clone());
+                conn_cx.spawn(async move {
+                    baseline::capture_at_session_start(&session).await;
+                    Ok(())
+                })
+            }
+        })
+}
+
+/// The cargo tools as a plain MCP server, for MCP hosts that do not speak ACP. The whole
+/// connection is one session, so there are no automatic checks after a turn, no progress
+/// tool calls and no permission prompts; MCP hosts usually ask before each tool call anyway.
+pub struct CargoMcpServer {
+    cwd: Option<String>,
+    config: ConfigSources,
+    tool_policy: ToolPolicy,
+}
+
+impl CargoMcpServer {
+    pub fn new() -> Self {
+        Self {
+            cwd: None,
+            config: ConfigSources::default(),
+            tool_policy: ToolPolicy::default(),
+        }
+    }
+
+    /// Where the configuration is read from; by default the user and project files
+    pub fn config_sources(mut self, sources: ConfigSources) -> Self {
+        self.config = sources;
+        self
+    }
+
+    /// Which tools are offered. A plain MCP client cannot ask the user for permission, so tools
+    /// that need it fail when called; disable them instead.
+    pub fn tool_policy(mut self, policy: ToolPolicy) -> Self {
+        self.tool_policy = policy;
+        self
+    }
+
+    /// The directory commands run in unless a tool call or `set_cwd` says otherwise; defaults
+    /// to the directory the server was started in
+    pub fn cwd(mut self, cwd: impl Into<String>) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+}
+
+impl Default for CargoMcpServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Component<McpServerToClient> for CargoMcpServer {
+    async fn serve(self, client: impl Component<McpClientToServer>) -> Result<(), sacp::Error> {
+        serve_mcp(
+            self.cwd,
+            self.config,
+            self.tool_policy,
+            DynComponent::new(client),
+        )
+        .await
+    }
+}
+
+/// Serve the tools to `client`
+async fn serve_mcp(
+    cwd: Option<String>,
+    config: ConfigSources,
+    tool_policy: ToolPolicy,
+    client: DynComponent<McpClientToServer>,
+) -> Result<(), sacp::Error> {
+    let cwd = match cwd {
+        Some(cwd) => Some(cwd),
+        None => std::env::current_dir()
+            .ok()
+            .map(|dir| dir.display().to_string()),
+    };
+    let session = Arc::new(
+        SessionState::new(cwd)
+            .with_config_sources(Arc::new(config))
+            .with_progress_updates(false)
+            .with_permission_prompts(false),
+    );
+    let server = build_mcp_server::<McpServerToClient>(session.clone(), tool_policy)?;
+    cancellation::serve(session, server, client).await
+}
 
 impl Component<ProxyToConductor> for CargoProxy {
     async fn serve(self, client: impl Component<ConductorToProxy>) -> Result<(), sacp::Error> {
-        let cwd = Arc::new(RwLock::new(None));
-        let has_unchecked_changes_to_rs_files = Arc::new(Mutex::new(false));
-        ProxyToConductor::builder()
+        let config = Arc::new(self.config);
+        let tool_policy = Arc::new(self.tool_policy);
+        let progress_updates = self.progress_updates;
+        let sessions = Sessions::default();
+        let result = ProxyToConductor::builder()
             .name("cargo-proxy")
-            .with_mcp_server(build_mcp_server(cwd.clone()))
             .on_receive_request_from(
                 ClientPeer,
                 {
-                    let cwd = cwd.clone();
-                    let has_unchecked_changes_to_rs_files = has_unchecked_changes_to_rs_files.clone();
+                    let sessions = sessions.clone();
+                    async move |new_session_req: NewSessionRequest, req_cx, conn_cx| {
+                        start_session(
+                            new_session_req,
+                            req_cx,
+                            conn_cx,
+                            sessions.clone(),
+                            config.clone(),
+                            &tool_policy,
+                            progress_updates,
+                        )
+                    }
+                },
+                on_receive_request!(),
+            )
+            .on_receive_request_from(
+                ClientPeer,
+                {
+                    let sessions = sessions.clone();
                     async move |prompt_req: PromptRequest, req_cx, conn_cx| {
+                        // A prompt from the user starts a fresh budget of automatic follow-ups
+                        let session = sessions.get(&prompt_req.session_id);
+                        if let Some(session) = &session {
+                            session.reset_auto_check();
+                        }
+
                         conn_cx
                             .send_request_to(AgentPeer, prompt_req.clone())
                             .on_receiving_ok_result(req_cx, {
-                                let cwd = cwd.clone();
-                                let has_unchecked_changes_to_rs_files = has_unchecked_changes_to_rs_files.clone();
+                                let conn_cx = conn_cx.clone();
                                 move |res, req_cx| async move {
                                     req_cx.respond(res.clone())?;
-                                    match res.stop_reason {
-                                        sacp::schema::StopReason::EndTurn => {
-                                            let has_unchecked_changes_to_rs_files = std::mem::take(
-                                                &mut *has_unchecked_changes_to_rs_files.lock().expect("not poisoned"),
-                                            );
-                                            if !has_unchecked_changes_to_rs_files {
-                                                return Ok(());
-                                            }
-                                            let cwd = cwd.read().await.clone();
-
-                                            let res = crate::cargo_command::execute_cargo_command("check", vec![], cwd, false).await?;
-                                            if let Some(0) = res.exit_code {
-                                                return Ok(());
-                                            }
-                                            let json = serde_json::to_string(&res)?;
-                                            let content = sacp::schema::ContentBlock::Text(TextContent::new(indoc::formatdoc! {"
-                                                Cargo check has automatically been run and the project failed to build with the following output. You may wish to fix the errors.
-
-                                                {json}
-                                            "}));
-                                            conn_cx.send_request_to(AgentPeer, PromptRequest::new(prompt_req.session_id.clone(), vec![content]));
-
-                                            let content = sacp::schema::ContentBlock::Text(TextContent::new(indoc::formatdoc! {"
-                                                Cargo check has automatically been run and the project failed to build with the following output (omitted). You may wish to fix the errors.
-                                            "}));
-                                            conn_cx.send_notification_to(ClientPeer, SessionNotification::new(prompt_req.session_id, SessionUpdate::UserMessageChunk(ContentChunk::new(content))))?;
-
-                                            Ok(())
+                                    match (res.stop_reason, session) {
+                                        (sacp::schema::StopReason::EndTurn, Some(session)) => {
+                                            auto_check::check_after_turn(
+                                                conn_cx,
+                                                prompt_req.session_id,
+                                                session,
+                                            )
+                                            .await
                                         }
                                         _ => Ok(()),
                                     }
@@ -72,10 +256,25 @@ impl Component<ProxyToConductor> for CargoProxy {
                 },
                 on_receive_request!(),
             )
+            .on_receive_notification_from(
+                ClientPeer,
+                {
+                    let sessions = sessions.clone();
+                    async move |cancel: CancelNotification, cx| {
+                        if let Some(session) = sessions.get(&cancel.session_id) {
+                            session.cancel_running();
+                        }
+
+                        cx.send_notification_to(AgentPeer, cancel)?;
+                        Ok(())
+                    }
+                },
+                sacp::on_receive_notification!(),
+            )
             .on_receive_notification_from(
                 AgentPeer,
                 {
-                    let has_unchecked_changes_to_rs_files = has_unchecked_changes_to_rs_files.clone();
+                    let sessions = sessions.clone();
                     async move |notification: SessionNotification, cx| {
                         if let SessionUpdate::ToolCallUpdate(update) = &notification.update
                             && update
@@ -83,21 +282,20 @@ impl Component<ProxyToConductor> for CargoProxy {
                                 .status
                                 .map(|s| s == ToolCallStatus::Completed)
                                 .unwrap_or(false)
-                            && update
-                                .fields
-                                .locations
-                                .as_ref()
-                                .map(|l| {
-                                    l.iter().any(|l| {
+                            && let Some(locations) = &update.fields.locations
+                            && let Some(session) = sessions.get(&notification.session_id)
+                        {
+                            session.record_rs_edits(
+                                locations
+                                    .iter()
+                                    .filter(|l| {
                                         l.path
                                             .extension()
                                             .map(|e| e.eq_ignore_ascii_case("rs"))
                                             .unwrap_or(false)
                                     })
-                                })
-                                .unwrap_or(false)
-                        {
-                            *has_unchecked_changes_to_rs_files.lock().expect("not poisoned") = true;
+                                    .map(|l| l.path.clone()),
+                            );
                         }
 
                         cx.send_notification_to(ClientPeer, notification)?;
@@ -107,6 +305,8 @@ impl Component<ProxyToConductor> for CargoProxy {
                 sacp::on_receive_notification!(),
             )
             .serve(client)
-            .await
+            .await;
+        sessions.clear();
+        result
     }
 }
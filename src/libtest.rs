@@ -0,0 +1,299 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::cargo_command::{CargoCommandJsonResult, ToolResponse, Truncation, keep_within};
+use crate::toolchain;
+
+/// Arguments that make libtest report results as JSON lines; needs a nightly toolchain
+pub const JSON_FORMAT_ARGS: [&str; 5] = [
+    "-Z",
+    "unstable-options",
+    "--format",
+    "json",
+    "--report-time",
+];
+
+/// Outcome of a single test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TestStatus {
+    Ok,
+    Failed,
+    Ignored,
+}
+
+/// A single test as reported by the test harness
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct TestCase {
+    pub name: String,
+    pub status: TestStatus,
+    /// Only known when the harness reports test times (`--report-time`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_secs: Option<f64>,
+    /// What the test printed before it failed, including the panic message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+/// Number of tests with each outcome, across all test binaries
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct TestSummary {
+    pub passed: usize,
+    pub failed: usize,
+    pub ignored: usize,
+}
+
+/// Result of `cargo test`, with the harness output parsed into individual tests
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CargoTestResult {
+    #[serde(flatten)]
+    pub command: CargoCommandJsonResult,
+    pub summary: TestSummary,
+    pub tests: Vec<TestCase>,
+}
+
+impl From<CargoCommandJsonResult> for CargoTestResult {
+    fn from(mut command: CargoCommandJsonResult) -> Self {
+        let (tests, rest) = parse_test_output(&command.stdout);
+        command.stdout = rest;
+        let mut summary = TestSummary::default();
+        for test in &tests {
+            match test.status {
+                TestStatus::Ok => summary.passed += 1,
+                TestStatus::Failed => summary.failed += 1,
+                TestStatus::Ignored => summary.ignored += 1,
+            }
+        }
+        CargoTestResult {
+            command,
+            summary,
+            tests,
+        }
+    }
+}
+
+/// Failed tests are kept before the others
+impl ToolResponse for CargoTestResult {
+    fn command_mut(&mut self) -> &mut CargoCommandJsonResult {
+        &mut self.command
+    }
+
+    fn trim_parts(&mut self, limit: usize, truncation: &mut Truncation) {
+        self.tests
+            .sort_by_key(|test| test.status != TestStatus::Failed);
+        truncation.elided_entries += keep_within(&mut self.tests, limit);
+    }
+}
+
+/// Whether the toolchain used in `cwd` can produce libtest's JSON output
+pub async fn supports_json_format(toolchain: Option<&str>, cwd: Option<&str>) -> bool {
+    toolchain::rustc_version(toolchain, cwd)
+        .await
+        .is_some_and(|version| version.contains("nightly"))
+}
+
+/// Extract the tests from libtest output, in either its JSON or its default human-readable
+/// format. Returns the tests and the output with the JSON events removed.
+fn parse_test_output(stdout: &str) -> (Vec<TestCase>, String) {
+    let mut tests = Vec::new();
+    let mut rest = String::new();
+    for line in stdout.lines() {
+        match serde_json::from_str::<JsonEvent>(line) {
+            Ok(event) => tests.extend(event.into_test_case()),
+            Err(_) => {
+                rest.push_str(line);
+                rest.push('\n');
+            }
+        }
+    }
+    parse_human_output(&rest, &mut tests);
+    (tests, rest)
+}
+
+/// A line of libtest's JSON output
+#[derive(Deserialize)]
+struct JsonEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    event: String,
+    name: Option<String>,
+    exec_time: Option<f64>,
+    stdout: Option<String>,
+}
+
+impl JsonEvent {
+    fn into_test_case(self) -> Option<TestCase> {
+        if self.kind != "test" {
+            return None;
+        }
+        let status = match self.event.as_str() {
+            "ok" => TestStatus::Ok,
+            "failed" => TestStatus::Failed,
+            "ignored" => TestStatus::Ignored,
+            _ => return None,
+        };
+        Some(TestCase {
+            name: self.name?,
+            status,
+            duration_secs: self.exec_time,
+            output: self.stdout.filter(|s| !s.is_empty()),
+        })
+    }
+}
+
+/// Parse lines like `test parser::tests::empty ... FAILED` and attach the output printed
+/// under `---- parser::tests::empty stdout ----` to the failed tests
+fn parse_human_output(stdout: &str, tests: &mut Vec<TestCase>) {
+    let first = tests.len();
+    let mut section: Option<(String, Vec<&str>)> = None;
+    let mut sections = Vec::new();
+
+    for line in stdout.lines() {
+        if let Some(header) = line
+            .strip_prefix("---- ")
+            .and_then(|l| l.strip_suffix(" ----"))
+        {
+            sections.extend(section.take());
+            let name = header
+                .strip_suffix(" stdout")
+                .or_else(|| header.strip_suffix(" stderr"))
+                .unwrap_or(header);
+            section = Some((name.to_string(), Vec::new()));
+            continue;
+        }
+        if let Some((_, lines)) = &mut section {
+            if line == "failures:" || line.starts_with("test result:") {
+                sections.extend(section.take());
+            } else {
+                lines.push(line);
+            }
+            continue;
+        }
+        if let Some(test) = parse_result_line(line) {
+            tests.push(test);
+        }
+    }
+    sections.extend(section);
+
+    for (name, lines) in sections {
+        if let Some(test) = tests[first..]
+            .iter_mut()
+            .find(|t| t.name == name && t.status == TestStatus::Failed && t.output.is_none())
+        {
+            test.output = Some(lines.join("\n").trim_end().to_string());
+        }
+    }
+}
+
+fn parse_result_line(line: &str) -> Option<TestCase> {
+    let (name, outcome) = line.strip_prefix("test ")?.rsplit_once(" ... ")?;
+    let (outcome, duration_secs) = match outcome.split_once(" <") {
+        Some((outcome, time)) => (
+            outcome,
+            time.strip_suffix("s>").and_then(|t| t.parse().ok()),
+        ),
+        None => (outcome, None),
+    };
+    let status = match outcome {
+        "ok" => TestStatus::Ok,
+        "FAILED" => TestStatus::Failed,
+        _ if outcome.starts_with("ignored") => TestStatus::Ignored,
+        _ => return None,
+    };
+    Some(TestCase {
+        name: name.to_string(),
+        status,
+        duration_secs,
+        output: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo_command::{OutputBudget, fit_to_budget};
+
+    #[test]
+    fn test_parse_human_output() {
+        let stdout = indoc::indoc! {"
+            running 4 tests
+            test tests::a ... ok
+            test tests::b ... FAILED
+            test tests::c ... ignored, slow
+            test tests::d ... ok <0.250s>
+
+            failures:
+
+            ---- tests::b stdout ----
+            hello
+
+            thread 'tests::b' panicked at src/lib.rs:4:41:
+            assertion failed
+
+
+            failures:
+                tests::b
+
+            test result: FAILED. 2 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.02s
+        "};
+        let (tests, _) = parse_test_output(stdout);
+        let statuses: Vec<_> = tests.iter().map(|t| (t.name.as_str(), t.status)).collect();
+        assert_eq!(
+            statuses,
+            [
+                ("tests::a", TestStatus::Ok),
+                ("tests::b", TestStatus::Failed),
+                ("tests::c", TestStatus::Ignored),
+                ("tests::d", TestStatus::Ok),
+            ]
+        );
+        assert_eq!(
+            tests[1].output.as_deref(),
+            Some("hello\n\nthread 'tests::b' panicked at src/lib.rs:4:41:\nassertion failed")
+        );
+        assert_eq!(tests[3].duration_secs, Some(0.25));
+    }
+
+    #[test]
+    fn test_parse_json_output() {
+        let stdout = indoc::indoc! {r#"
+            { "type": "suite", "event": "started", "test_count": 2 }
+            { "type": "test", "event": "started", "name": "tests::a" }
+            { "type": "test", "name": "tests::a", "event": "ok", "exec_time": 0.5 }
+            { "type": "test", "name": "tests::b", "event": "failed", "exec_time": 0.1, "stdout": "boom\n" }
+            { "type": "suite", "event": "failed", "passed": 1, "failed": 1, "ignored": 0, "measured": 0, "filtered_out": 0, "exec_time": 0.6 }
+            note: run with `RUST_BACKTRACE=1`
+        "#};
+        let (tests, rest) = parse_test_output(stdout);
+        assert_eq!(tests.len(), 2);
+        assert_eq!(tests[0].duration_secs, Some(0.5));
+        assert_eq!(tests[1].status, TestStatus::Failed);
+        assert_eq!(tests[1].output.as_deref(), Some("boom\n"));
+        assert_eq!(rest, "note: run with `RUST_BACKTRACE=1`\n");
+    }
+
+    #[test]
+    fn test_fit_to_budget() {
+        let mut stdout: String = (0..500)
+            .map(|i| format!("test tests::passing_{i} ... ok\n"))
+            .collect();
+        stdout.push_str("test tests::failing ... FAILED\n");
+        let command = CargoCommandJsonResult {
+            stdout,
+            ..CargoCommandJsonResult::for_test("cargo test", vec![])
+        };
+        let mut result = CargoTestResult::from(command);
+        fit_to_budget(
+            &mut result,
+            &OutputBudget {
+                max_bytes: Some(2_000),
+                max_tokens: None,
+            },
+        );
+        assert!(serde_json::to_string(&result).unwrap().len() <= 2_000);
+        assert_eq!(result.summary.passed, 500);
+        assert_eq!(result.tests[0].name, "tests::failing");
+        let truncation = result.command.truncated.unwrap();
+        assert_eq!(truncation.elided_entries + result.tests.len(), 501);
+    }
+}
@@ -1,20 +1,143 @@
+use std::path::PathBuf;
+
 use anyhow::Result;
+use clap::Parser;
 use sacp::component::Component;
-use symposium_cargo::CargoProxy;
+use symposium_cargo::{CargoMcpServer, CargoProxy, ConfigSources};
+
+/// Cargo commands with structured output for agents, as an ACP proxy or a plain MCP server.
+///
+/// Settings come from the built-in defaults, then the user configuration
+/// (`~/.config/symposium/cargo.toml`), then the project's `.symposium/cargo.toml`, then these
+/// options, each overriding the ones before.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Serve the cargo tools as a plain MCP server, for hosts that do not speak ACP
+    #[arg(long)]
+    mcp: bool,
+    /// Directory commands run in by default
+    #[arg(long, value_name = "DIR")]
+    cwd: Option<PathBuf>,
+    /// Read the user configuration from FILE
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+    /// Ignore the user configuration file
+    #[arg(long, conflicts_with = "config")]
+    no_user_config: bool,
+    /// Only offer these tools
+    #[arg(long = "enable-tool", value_name = "TOOL", value_delimiter = ',')]
+    enable_tools: Vec<String>,
+    /// Leave these tools out
+    #[arg(long = "disable-tool", value_name = "TOOL", value_delimiter = ',')]
+    disable_tools: Vec<String>,
+    /// Ask the user before each call to these tools
+    #[arg(long = "confirm-tool", value_name = "TOOL", value_delimiter = ',')]
+    confirm_tools: Vec<String>,
+    /// Seconds a cargo command may run before it is killed, unless the tool call or a
+    /// per-tool entry under `[timeouts]` says otherwise. Sets `timeouts.default`.
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+    /// Trim tool results to this many bytes
+    #[arg(long, value_name = "BYTES")]
+    max_output_bytes: Option<u64>,
+    /// Trim tool results to about this many tokens
+    #[arg(long, value_name = "TOKENS")]
+    max_output_tokens: Option<u64>,
+    /// Do not check the project after turns in which the agent edited Rust files
+    #[arg(long)]
+    no_auto_check: bool,
+    /// How many times in a row the agent is prompted to fix a failing automatic check
+    #[arg(long, value_name = "N")]
+    max_auto_check_followups: Option<u64>,
+    /// Do not show the progress of long cargo commands to the client
+    #[arg(long)]
+    no_progress: bool,
+    /// Print the configuration that applies in the working directory and exit
+    #[arg(long)]
+    print_config: bool,
+}
+
+impl Cli {
+    /// The configuration sources, with the options given on the command line as overrides
+    fn config_sources(&self) -> Result<ConfigSources> {
+        let mut sources = ConfigSources::default();
+        if self.no_user_config {
+            sources.user = None;
+        }
+        if let Some(config) = &self.config {
+            anyhow::ensure!(config.is_file(), "{} does not exist", config.display());
+            sources.user = Some(config.clone());
+        }
+        if let Some(cwd) = &self.cwd {
+            sources.set(&["cwd"], std::path::absolute(cwd)?.display().to_string());
+        }
+        if !self.enable_tools.is_empty() {
+            sources.set(&["tools", "enabled"], self.enable_tools.clone());
+        }
+        if !self.disable_tools.is_empty() {
+            sources.set(&["tools", "disabled"], self.disable_tools.clone());
+        }
+        if !self.confirm_tools.is_empty() {
+            sources.set(&["tools", "confirm"], self.confirm_tools.clone());
+        }
+        if let Some(secs) = self.timeout {
+            sources.set(&["timeouts", "default"], secs as i64);
+        }
+        if let Some(bytes) = self.max_output_bytes {
+            sources.set(&["output", "max-bytes"], bytes as i64);
+        }
+        if let Some(tokens) = self.max_output_tokens {
+            sources.set(&["output", "max-tokens"], tokens as i64);
+        }
+        if self.no_auto_check {
+            sources.set(&["auto-check", "enabled"], false);
+        }
+        if let Some(max) = self.max_auto_check_followups {
+            sources.set(&["auto-check", "max-followups"], max as i64);
+        }
+        Ok(sources)
+    }
+}
 
-/// Run the proxy as a standalone binary connected to stdio
+/// Run the proxy as a standalone binary connected to stdio, or with `--mcp` serve the cargo
+/// tools as a plain MCP server for hosts that do not speak ACP
 pub async fn run() -> Result<()> {
+    let cli = Cli::parse();
+    let sources = cli.config_sources()?;
+
+    if cli.print_config {
+        let cwd = match &cli.cwd {
+            Some(cwd) => std::path::absolute(cwd)?,
+            None => std::env::current_dir()?,
+        };
+        print!("{}", sources.render(&cwd)?);
+        return Ok(());
+    }
+
     // Initialize tracing
     tracing_subscriber::fmt()
         .with_env_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
                 .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new("info")),
         )
+        .with_writer(std::io::stderr)
         .init();
 
-    tracing::info!("Starting cargo-proxy");
-
-    CargoProxy.serve(sacp_tokio::Stdio::new()).await?;
+    if cli.mcp {
+        tracing::info!("Starting cargo-mcp");
+        CargoMcpServer::new()
+            .config_sources(sources)
+            .serve(sacp_tokio::Stdio::new())
+            .await?;
+    } else {
+        tracing::info!("Starting cargo-proxy");
+        CargoProxy::new()
+            .config_sources(sources)
+            .progress_updates(!cli.no_progress)
+            .serve(sacp_tokio::Stdio::new())
+            .await?;
+    }
 
     Ok(())
 }
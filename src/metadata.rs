@@ -0,0 +1,317 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::cargo_command::{
+    CargoCommandJsonResult, ToolResponse, Truncation, execute_cargo_command, json_len, keep_within,
+};
+
+/// The parts of `cargo metadata` an agent usually needs, for the workspace members only
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WorkspaceInfo {
+    pub workspace_root: String,
+    pub members: Vec<PackageInfo>,
+    /// Every package in the build and the packages it depends on, as `name@version`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolve: Option<BTreeMap<String, Vec<String>>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PackageInfo {
+    pub name: String,
+    pub version: String,
+    pub manifest_path: String,
+    pub targets: Vec<TargetInfo>,
+    /// Declared features and what each one enables
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub features: BTreeMap<String, Vec<String>>,
+    pub dependencies: Vec<DependencyInfo>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TargetInfo {
+    pub name: String,
+    /// e.g. `lib`, `bin`, `test`, `example`, `bench`, `custom-build`
+    pub kind: Vec<String>,
+    pub src_path: String,
+}
+
+/// A dependency declared in the package's manifest
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DependencyInfo {
+    pub name: String,
+    /// Version requirement from the manifest
+    pub req: String,
+    /// `dev` or `build`; normal dependencies have no kind
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub optional: bool,
+    /// Name the dependency is imported under, if renamed in the manifest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rename: Option<String>,
+    /// Version picked by the resolver; only known when the resolve graph was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<String>,
+}
+
+/// Result of `cargo metadata`, with its output replaced by a [`WorkspaceInfo`]
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CargoMetadataResult {
+    #[serde(flatten)]
+    pub command: CargoCommandJsonResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<WorkspaceInfo>,
+}
+
+impl From<CargoCommandJsonResult> for CargoMetadataResult {
+    fn from(mut command: CargoCommandJsonResult) -> Self {
+        let workspace = serde_json::from_str::<RawMetadata>(&command.stdout)
+            .ok()
+            .map(RawMetadata::into_workspace_info);
+        if workspace.is_some() {
+            command.stdout.clear();
+        }
+        CargoMetadataResult { command, workspace }
+    }
+}
+
+/// The resolve graph goes first, then members from the end
+impl ToolResponse for CargoMetadataResult {
+    fn command_mut(&mut self) -> &mut CargoCommandJsonResult {
+        &mut self.command
+    }
+
+    fn trim_parts(&mut self, limit: usize, truncation: &mut Truncation) {
+        let Some(workspace) = &mut self.workspace else {
+            return;
+        };
+        if let Some(resolve) = workspace.resolve.take() {
+            truncation.elided_entries += resolve.len();
+        }
+        let mut members = std::mem::take(&mut workspace.members);
+        let rest = json_len(&*workspace);
+        truncation.elided_entries += keep_within(&mut members, limit.saturating_sub(rest));
+        workspace.members = members;
+    }
+}
+
+/// Load the workspace members of the project in `cwd`, without resolving dependencies
+pub(crate) async fn load_workspace(cwd: Option<&str>) -> anyhow::Result<WorkspaceInfo> {
+    let result = execute_cargo_command(
+        "metadata",
+        vec!["--format-version", "1", "--offline", "--no-deps"],
+        cwd.map(str::to_string),
+        true,
+        Default::default(),
+    )
+    .await?;
+    let result = CargoMetadataResult::from(result);
+    result.workspace.ok_or_else(|| {
+        anyhow::anyhow!(
+            "failed to read the workspace metadata: {}",
+            result.command.stderr
+        )
+    })
+}
+
+/// The root directory of the workspace in `cwd`
+pub(crate) async fn workspace_root(cwd: Option<&str>) -> anyhow::Result<PathBuf> {
+    let result = execute_cargo_command(
+        "locate-project",
+        vec!["--workspace", "--message-format", "plain"],
+        cwd.map(str::to_string),
+        true,
+        Default::default(),
+    )
+    .await?;
+    let manifest = Path::new(result.stdout.trim());
+    match manifest.parent() {
+        Some(root) if result.exit_code == Some(0) => Ok(root.to_path_buf()),
+        _ => anyhow::bail!("failed to locate the workspace: {}", result.stderr),
+    }
+}
+
+/// The subset of `cargo metadata --format-version 1` that we consume
+#[derive(Deserialize)]
+struct RawMetadata {
+    packages: Vec<RawPackage>,
+    workspace_members: Vec<String>,
+    workspace_root: String,
+    resolve: Option<RawResolve>,
+}
+
+#[derive(Deserialize)]
+struct RawPackage {
+    id: String,
+    name: String,
+    version: String,
+    manifest_path: String,
+    targets: Vec<TargetInfo>,
+    #[serde(default)]
+    features: BTreeMap<String, Vec<String>>,
+    dependencies: Vec<RawDependency>,
+}
+
+#[derive(Deserialize)]
+struct RawDependency {
+    name: String,
+    req: String,
+    kind: Option<String>,
+    #[serde(default)]
+    optional: bool,
+    rename: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RawResolve {
+    nodes: Vec<RawNode>,
+}
+
+#[derive(Deserialize)]
+struct RawNode {
+    id: String,
+    #[serde(default)]
+    deps: Vec<RawNodeDep>,
+}
+
+#[derive(Deserialize)]
+struct RawNodeDep {
+    /// Crate name the dependency is imported under
+    name: String,
+    pkg: String,
+}
+
+impl RawMetadata {
+    fn into_workspace_info(self) -> WorkspaceInfo {
+        let names: HashMap<&str, String> = self
+            .packages
+            .iter()
+            .map(|p| (p.id.as_str(), format!("{}@{}", p.name, p.version)))
+            .collect();
+        let versions: HashMap<&str, &str> = self
+            .packages
+            .iter()
+            .map(|p| (p.id.as_str(), p.version.as_str()))
+            .collect();
+        let nodes: HashMap<&str, &RawNode> = self
+            .resolve
+            .iter()
+            .flat_map(|r| &r.nodes)
+            .map(|n| (n.id.as_str(), n))
+            .collect();
+
+        let members = self
+            .packages
+            .iter()
+            .filter(|p| self.workspace_members.contains(&p.id))
+            .map(|p| PackageInfo {
+                name: p.name.clone(),
+                version: p.version.clone(),
+                manifest_path: p.manifest_path.clone(),
+                targets: p.targets.clone(),
+                features: p.features.clone(),
+                dependencies: p
+                    .dependencies
+                    .iter()
+                    .map(|d| {
+                        let crate_name = d.rename.as_deref().unwrap_or(&d.name).replace('-', "_");
+                        let resolved = nodes
+                            .get(p.id.as_str())
+                            .and_then(|n| n.deps.iter().find(|dep| dep.name == crate_name))
+                            .and_then(|dep| versions.get(dep.pkg.as_str()))
+                            .map(|v| v.to_string());
+                        DependencyInfo {
+                            name: d.name.clone(),
+                            req: d.req.clone(),
+                            kind: d.kind.clone(),
+                            optional: d.optional,
+                            rename: d.rename.clone(),
+                            resolved,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let resolve = self.resolve.as_ref().map(|resolve| {
+            resolve
+                .nodes
+                .iter()
+                .filter_map(|node| {
+                    let deps = node
+                        .deps
+                        .iter()
+                        .filter_map(|d| names.get(d.pkg.as_str()).cloned())
+                        .collect();
+                    Some((names.get(node.id.as_str())?.clone(), deps))
+                })
+                .collect()
+        });
+
+        WorkspaceInfo {
+            workspace_root: self.workspace_root,
+            members,
+            resolve,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workspace_info() {
+        let metadata = serde_json::json!({
+            "packages": [
+                {
+                    "id": "path+file:///p#app@0.1.0",
+                    "name": "app",
+                    "version": "0.1.0",
+                    "manifest_path": "/p/Cargo.toml",
+                    "targets": [{ "name": "app", "kind": ["bin"], "src_path": "/p/src/main.rs", "edition": "2024" }],
+                    "features": { "default": ["fast"], "fast": [] },
+                    "dependencies": [
+                        { "name": "serde-json", "req": "^1", "kind": null, "optional": false, "rename": null }
+                    ]
+                },
+                {
+                    "id": "registry+https://github.com/rust-lang/crates.io-index#serde-json@1.0.5",
+                    "name": "serde-json",
+                    "version": "1.0.5",
+                    "manifest_path": "/r/serde-json/Cargo.toml",
+                    "targets": [],
+                    "features": {},
+                    "dependencies": []
+                }
+            ],
+            "workspace_members": ["path+file:///p#app@0.1.0"],
+            "workspace_root": "/p",
+            "resolve": {
+                "nodes": [
+                    {
+                        "id": "path+file:///p#app@0.1.0",
+                        "deps": [{ "name": "serde_json", "pkg": "registry+https://github.com/rust-lang/crates.io-index#serde-json@1.0.5" }]
+                    },
+                    { "id": "registry+https://github.com/rust-lang/crates.io-index#serde-json@1.0.5", "deps": [] }
+                ]
+            }
+        });
+
+        let info = RawMetadata::deserialize(&metadata)
+            .unwrap()
+            .into_workspace_info();
+        assert_eq!(info.members.len(), 1);
+        let app = &info.members[0];
+        assert_eq!(app.targets[0].kind, ["bin"]);
+        assert_eq!(app.features["default"], ["fast"]);
+        assert_eq!(app.dependencies[0].resolved.as_deref(), Some("1.0.5"));
+        assert_eq!(
+            info.resolve.unwrap()["app@0.1.0"],
+            ["serde-json@1.0.5".to_string()]
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sacp::link::ProxyToConductor;
+use sacp::schema::{
+    ContentBlock, PermissionOption, PermissionOptionKind, RequestPermissionOutcome,
+    RequestPermissionRequest, SessionId, TextContent, ToolCallUpdate, ToolCallUpdateFields,
+    ToolKind,
+};
+use sacp::{ClientPeer, JrConnectionCx};
+
+/// Tools that change the manifest, the lockfile or the target directory in ways that cannot be
+/// undone, so the user is asked before they run
+pub(crate) const ASK_BY_DEFAULT: &[&str] =
+    &["cargo_add", "cargo_clean", "cargo_remove", "cargo_update"];
+
+const ALLOW: &str = "allow";
+const ALLOW_ALWAYS: &str = "allow-always";
+const REJECT: &str = "reject";
+
+/// The user's answer to a permission request
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Decision {
+    Allow,
+    /// Allow this call and every later call to the same tool in the session
+    AllowAlways,
+    Reject,
+}
+
+/// What calling `tool` with `input` will change, for the user to decide on. `cwd` is the
+/// directory the call runs in.
+pub(crate) fn summary(tool: &str, input: &serde_json::Value, cwd: Option<&str>) -> String {
+    let cwd = cwd.unwrap_or(".");
+    let mut summary = match (tool, input["package"].as_str()) {
+        ("cargo_add", Some(package)) => format!(
+            "Add `{package}` to the dependencies of the package in `{cwd}` and update its Cargo.lock"
+        ),
+        ("cargo_remove", Some(package)) => format!(
+            "Remove `{package}` from the dependencies of the package in `{cwd}` and update its Cargo.lock"
+        ),
+        ("cargo_update", Some(package)) => {
+            format!("Update `{package}` in the Cargo.lock of `{cwd}`")
+        }
+        ("cargo_update", None) => format!(
+            "Update every dependency in the Cargo.lock of `{cwd}` to its latest compatible version"
+        ),
+        ("cargo_clean", _) => format!(
+            "Delete the build artifacts of the workspace in `{cwd}`, so the next build starts from scratch"
+        ),
+        _ => format!("Run `{tool}` in `{cwd}`"),
+    };
+    let extra_args: Vec<&str> = input["extra_args"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|arg| arg.as_str())
+        .collect();
+    if !extra_args.is_empty() {
+        summary.push_str(&format!(", with `{}`", extra_args.join(" ")));
+    }
+    summary
+}
+
+/// Ask the client's user whether `tool` may run with `input`, with `session/request_permission`.
+/// A cancelled prompt counts as a no.
+pub(crate) async fn request(
+    cx: JrConnectionCx<ProxyToConductor>,
+    session_id: SessionId,
+    tool: &str,
+    summary: String,
+    input: serde_json::Value,
+) -> anyhow::Result<Decision> {
+    let title = match input["package"].as_str() {
+        Some(package) => format!("{} {package}", tool.replace('_', " ")),
+        None => tool.replace('_', " "),
+    };
+    let tool_call = ToolCallUpdate::new(
+        next_tool_call_id(),
+        ToolCallUpdateFields::new()
+            .title(title)
+            .kind(ToolKind::Execute)
+            .content(vec![ContentBlock::Text(TextContent::new(summary)).into()])
+            .raw_input(input),
+    );
+    let options = vec![
+        PermissionOption::new(ALLOW, "Allow", PermissionOptionKind::AllowOnce),
+        PermissionOption::new(
+            ALLOW_ALWAYS,
+            format!("Always allow `{tool}` in this session"),
+            PermissionOptionKind::AllowAlways,
+        ),
+        PermissionOption::new(REJECT, "Reject", PermissionOptionKind::RejectOnce),
+    ];
+    let response = cx
+        .send_request_to(
+            ClientPeer,
+            RequestPermissionRequest::new(session_id, tool_call, options),
+        )
+        .block_task()
+        .await?;
+    Ok(match response.outcome {
+        RequestPermissionOutcome::Selected(selected) => match &*selected.option_id.0 {
+            ALLOW => Decision::Allow,
+            ALLOW_ALWAYS => Decision::AllowAlways,
+            _ => Decision::Reject,
+        },
+        _ => Decision::Reject,
+    })
+}
+
+fn next_tool_call_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!("cargo-permission-{}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(
+            summary("cargo_add", &json!({"package": "serde"}), Some("/p")),
+            "Add `serde` to the dependencies of the package in `/p` and update its Cargo.lock"
+        );
+        assert_eq!(
+            summary(
+                "cargo_update",
+                &json!({"extra_args": ["--precise", "1.0.1"]}),
+                Some("/p")
+            ),
+            "Update every dependency in the Cargo.lock of `/p` to its latest compatible version, with `--precise 1.0.1`"
+        );
+        assert_eq!(
+            summary("cargo_run", &json!({}), None),
+            "Run `cargo_run` in `.`"
+        );
+    }
+}
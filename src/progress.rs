@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use sacp::link::ProxyToConductor;
+use sacp::schema::{
+    ContentBlock, SessionId, SessionNotification, SessionUpdate, TextContent, ToolCall,
+    ToolCallStatus, ToolCallUpdate, ToolCallUpdateFields, ToolKind,
+};
+use sacp::{ClientPeer, JrConnectionCx};
+
+use crate::cargo_command::{Progress, ProgressCallback};
+
+/// Commands that finish sooner than this never show up in the client
+const SHOW_AFTER: Duration = Duration::from_secs(1);
+
+/// Minimum time between two updates of the same tool call
+const UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Mirrors the progress of a cargo command to the ACP client as a tool call of its own, so the
+/// user can follow long builds while the agent waits for the result
+struct ToolCallMirror {
+    cx: JrConnectionCx<ProxyToConductor>,
+    session_id: SessionId,
+    title: String,
+    state: Mutex<MirrorState>,
+}
+
+struct MirrorState {
+    tool_call_id: Option<String>,
+    last_update: Instant,
+}
+
+impl ToolCallMirror {
+    fn report(&self, progress: &Progress) {
+        let mut state = self.state.lock().expect("not poisoned");
+        let update = match (&state.tool_call_id, progress.finished) {
+            (None, Some(_)) => return,
+            (None, None) if progress.elapsed < SHOW_AFTER => return,
+            (None, None) => {
+                let id = next_tool_call_id();
+                state.tool_call_id = Some(id.clone());
+                SessionUpdate::ToolCall(
+                    ToolCall::new(id, self.title.clone())
+                        .kind(ToolKind::Execute)
+                        .status(ToolCallStatus::InProgress)
+                        .content(vec![text(progress.summary())]),
+                )
+            }
+            (Some(id), finished) => {
+                let status = match finished {
+                    Some(true) => ToolCallStatus::Completed,
+                    Some(false) => ToolCallStatus::Failed,
+                    None if state.last_update.elapsed() < UPDATE_INTERVAL => return,
+                    None => ToolCallStatus::InProgress,
+                };
+                SessionUpdate::ToolCallUpdate(ToolCallUpdate::new(
+                    id.clone(),
+                    ToolCallUpdateFields::new()
+                        .status(status)
+                        .content(vec![text(progress.summary())]),
+                ))
+            }
+        };
+        state.last_update = Instant::now();
+
+        if let Err(err) = self.cx.send_notification_to(
+            ClientPeer,
+            SessionNotification::new(self.session_id.clone(), update),
+        ) {
+            tracing::warn!(?err, "failed to send cargo progress to the client");
+        }
+    }
+}
+
+fn text(text: String) -> sacp::schema::ToolCallContent {
+    ContentBlock::Text(TextContent::new(text)).into()
+}
+
+fn next_tool_call_id() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!("cargo-progress-{}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// A progress callback that shows the command as a tool call titled `title` in the client
+pub(crate) fn mirror_to_client(
+    cx: JrConnectionCx<ProxyToConductor>,
+    session_id: SessionId,
+    title: String,
+) -> ProgressCallback {
+    let mirror = ToolCallMirror {
+        cx,
+        session_id,
+        title,
+        state: Mutex::new(MirrorState {
+            tool_call_id: None,
+            last_update: Instant::now(),
+        }),
+    };
+    Arc::new(move |progress| mirror.report(progress))
+}
@@ -0,0 +1,347 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::cargo_command::{CargoCommandJsonResult, floor_char_boundary, json_len};
+use crate::diagnostic::{Diagnostic, DiagnosticLevel};
+
+/// How many results a session keeps; older ones are dropped first
+const MAX_RESULTS: usize = 20;
+
+/// Page size when the request does not give a `limit`
+const DEFAULT_DIAGNOSTICS_LIMIT: usize = 20;
+const DEFAULT_LINES_LIMIT: usize = 200;
+
+/// Most a page returns, whatever its `limit`, so that paging stays within the output budget
+const MAX_PAGE_BYTES: usize = 20_000;
+
+/// Lines longer than this are wrapped, and each part counts as a line of its own
+const MAX_LINE_BYTES: usize = 1_000;
+
+/// The complete results of a session's recent cargo commands, so that a trimmed response can
+/// be followed up with `get_cargo_result`
+#[derive(Default)]
+pub struct ResultStore {
+    state: Mutex<StoreState>,
+}
+
+#[derive(Default)]
+struct StoreState {
+    next_id: u64,
+    results: VecDeque<StoredResult>,
+}
+
+/// A result kept by the [`ResultStore`]
+#[derive(Debug, Clone)]
+pub struct StoredResult {
+    pub id: u64,
+    pub result: CargoCommandJsonResult,
+    /// When the command finished; files modified later may no longer match its diagnostics
+    pub finished_at: SystemTime,
+    /// Root of the workspace the command ran in, which the diagnostics' paths are relative to
+    pub workspace_root: Option<PathBuf>,
+}
+
+impl ResultStore {
+    /// Keep a copy of `result`, which ran in the workspace at `workspace_root`, and return the
+    /// id it can be fetched by
+    pub fn insert(&self, result: &CargoCommandJsonResult, workspace_root: Option<PathBuf>) -> u64 {
+        let mut state = self.state.lock().expect("not poisoned");
+        state.next_id += 1;
+        let id = state.next_id;
+        if state.results.len() == MAX_RESULTS {
+            state.results.pop_front();
+        }
+        state.results.push_back(StoredResult {
+            id,
+            result: result.clone(),
+            finished_at: SystemTime::now(),
+            workspace_root,
+        });
+        id
+    }
+
+    /// A copy of the stored result with `id`
+    pub fn get(&self, id: u64) -> Option<StoredResult> {
+        let state = self.state.lock().expect("not poisoned");
+        state.results.iter().find(|stored| stored.id == id).cloned()
+    }
+
+    /// The most recent stored result that `filter` accepts
+    pub fn latest(&self, filter: impl Fn(&CargoCommandJsonResult) -> bool) -> Option<StoredResult> {
+        let state = self.state.lock().expect("not poisoned");
+        state
+            .results
+            .iter()
+            .rev()
+            .find(|stored| filter(&stored.result))
+            .cloned()
+    }
+
+    /// Fetch one page of a stored result
+    pub fn page(&self, query: &ResultQuery) -> anyhow::Result<ResultPage> {
+        let state = self.state.lock().expect("not poisoned");
+        let Some(stored) = state.results.iter().find(|s| s.id == query.result_id) else {
+            anyhow::bail!(
+                "no result {}; only the results of the last {MAX_RESULTS} commands are kept",
+                query.result_id
+            );
+        };
+        Ok(query.page_of(&stored.result))
+    }
+}
+
+/// Which part of a result to page through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ResultSection {
+    #[default]
+    Diagnostics,
+    Stdout,
+    Stderr,
+}
+
+/// A request for part of a stored result
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ResultQuery {
+    /// The `result_id` of an earlier tool response
+    pub result_id: u64,
+    #[serde(default)]
+    pub section: ResultSection,
+    /// Diagnostics or lines to skip
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    /// Diagnostics or lines to return; defaults to 20 diagnostics or 200 lines. A page stops
+    /// early at about 20 KB, and lines longer than 1000 bytes are wrapped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// Only diagnostics whose primary span is in this file, e.g. `src/main.rs`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Only diagnostics of this level, e.g. `error` or `warning`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<DiagnosticLevel>,
+}
+
+/// One page of a stored result
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ResultPage {
+    pub result_id: u64,
+    pub command: String,
+    /// Diagnostics or lines that match the filters, across all pages
+    pub total: usize,
+    pub offset: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diagnostics: Option<Vec<Diagnostic>>,
+    /// The requested lines of stdout or stderr
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    /// The `offset` of the next page, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_offset: Option<usize>,
+}
+
+impl ResultQuery {
+    fn page_of(&self, result: &CargoCommandJsonResult) -> ResultPage {
+        let offset = self.offset.unwrap_or(0);
+        let mut page = ResultPage {
+            result_id: self.result_id,
+            command: result.command.clone(),
+            total: 0,
+            offset,
+            diagnostics: None,
+            text: None,
+            next_offset: None,
+        };
+        let mut bytes = 0;
+        // Always take the first entry, so that paging makes progress
+        let mut fits = |index: usize, len: usize| {
+            bytes += len;
+            index == 0 || bytes <= MAX_PAGE_BYTES
+        };
+        let taken = match self.section {
+            ResultSection::Diagnostics => {
+                let matching: Vec<_> = result
+                    .diagnostics
+                    .iter()
+                    .filter(|d| self.matches(d))
+                    .collect();
+                page.total = matching.len();
+                let limit = self.limit.unwrap_or(DEFAULT_DIAGNOSTICS_LIMIT);
+                let diagnostics: Vec<Diagnostic> = matching
+                    .into_iter()
+                    .skip(offset)
+                    .take(limit)
+                    .enumerate()
+                    .take_while(|(i, d)| fits(*i, json_len(d)))
+                    .map(|(_, d)| d.clone())
+                    .collect();
+                let taken = diagnostics.len();
+                page.diagnostics = Some(diagnostics);
+                taken
+            }
+            ResultSection::Stdout | ResultSection::Stderr => {
+                let text = match self.section {
+                    ResultSection::Stdout => &result.stdout,
+                    _ => &result.stderr,
+                };
+                let lines = wrapped_lines(text);
+                page.total = lines.len();
+                let limit = self.limit.unwrap_or(DEFAULT_LINES_LIMIT);
+                let lines: Vec<&str> = lines
+                    .into_iter()
+                    .skip(offset)
+                    .take(limit)
+                    .enumerate()
+                    .take_while(|(i, line)| fits(*i, line.len() + 1))
+                    .map(|(_, line)| line)
+                    .collect();
+                let taken = lines.len();
+                page.text = Some(lines.join("\n"));
+                taken
+            }
+        };
+        page.next_offset = Some(offset + taken).filter(|next| *next < page.total);
+        page
+    }
+
+    fn matches(&self, diagnostic: &Diagnostic) -> bool {
+        let level = self.level.is_none_or(|level| diagnostic.level == level);
+        let file = self.file.as_deref().is_none_or(|file| {
+            diagnostic
+                .spans
+                .iter()
+                .any(|s| s.is_primary && (s.file == file || s.file.ends_with(&format!("/{file}"))))
+        });
+        level && file
+    }
+}
+
+/// The lines of `text`, with those longer than [`MAX_LINE_BYTES`] wrapped
+fn wrapped_lines(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    for mut line in text.lines() {
+        while line.len() > MAX_LINE_BYTES {
+            let end = floor_char_boundary(line, MAX_LINE_BYTES);
+            lines.push(&line[..end]);
+            line = &line[end..];
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn diagnostic(level: DiagnosticLevel, file: &str) -> Diagnostic {
+        Diagnostic::for_test(level, &format!("{level:?} in {file}")).at(file, 1)
+    }
+
+    #[test]
+    fn test_pages() {
+        let store = ResultStore::default();
+        let diagnostics = vec![
+            diagnostic(DiagnosticLevel::Warning, "src/lib.rs"),
+            diagnostic(DiagnosticLevel::Error, "src/lib.rs"),
+            diagnostic(DiagnosticLevel::Error, "src/main.rs"),
+        ];
+        let result = CargoCommandJsonResult {
+            exit_code: Some(101),
+            stderr: "a\nb\nc\nd".to_string(),
+            build_success: false,
+            ..CargoCommandJsonResult::for_test("cargo check", diagnostics)
+        };
+        let id = store.insert(&result, None);
+
+        let query = |section, offset, limit, file: Option<&str>, level| ResultQuery {
+            result_id: id,
+            section,
+            offset,
+            limit,
+            file: file.map(str::to_string),
+            level,
+        };
+        let errors = store
+            .page(&query(
+                ResultSection::Diagnostics,
+                None,
+                None,
+                None,
+                Some(DiagnosticLevel::Error),
+            ))
+            .unwrap();
+        assert_eq!(errors.total, 2);
+        let in_lib = store
+            .page(&query(
+                ResultSection::Diagnostics,
+                Some(1),
+                None,
+                Some("lib.rs"),
+                None,
+            ))
+            .unwrap();
+        assert_eq!(in_lib.total, 2);
+        assert_eq!(
+            in_lib.diagnostics.unwrap()[0].message,
+            "Error in src/lib.rs"
+        );
+        let stderr = store
+            .page(&query(ResultSection::Stderr, Some(1), Some(2), None, None))
+            .unwrap();
+        assert_eq!((stderr.total, stderr.text.as_deref()), (4, Some("b\nc")));
+        assert_eq!(stderr.next_offset, Some(3));
+        assert!(
+            store
+                .page(&query(ResultSection::Stdout, None, None, None, None))
+                .is_ok()
+        );
+
+        for _ in 0..MAX_RESULTS {
+            store.insert(&result, None);
+        }
+        assert!(
+            store
+                .page(&query(ResultSection::Stdout, None, None, None, None))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_page_bytes() {
+        let store = ResultStore::default();
+        // One line of 50 KB, then 30 lines of 1 KB
+        let long = "x".repeat(50_000);
+        let short = format!("{}\n", "y".repeat(999)).repeat(30);
+        let result = CargoCommandJsonResult {
+            stdout: format!("{long}\n{short}"),
+            ..CargoCommandJsonResult::for_test("cargo run", vec![])
+        };
+        let id = store.insert(&result, None);
+        let page = |offset| {
+            store
+                .page(&ResultQuery {
+                    result_id: id,
+                    section: ResultSection::Stdout,
+                    offset,
+                    limit: None,
+                    file: None,
+                    level: None,
+                })
+                .unwrap()
+        };
+
+        let first = page(None);
+        assert_eq!(first.total, 80);
+        assert!(first.text.unwrap().len() <= MAX_PAGE_BYTES);
+        assert_eq!(first.next_offset, Some(19));
+        let last = page(Some(70));
+        assert_eq!(last.text.unwrap().lines().count(), 10);
+        assert_eq!(last.next_offset, None);
+    }
+}
@@ -0,0 +1,97 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::cargo_command::{CargoCommandJsonResult, ToolResponse, Truncation, truncate_middle};
+
+/// Result of `cargo run`, with the program's output split from cargo's own
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CargoRunResult {
+    #[serde(flatten)]
+    pub command: CargoCommandJsonResult,
+    /// Standard output of the program; missing when the build failed and it never ran
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program_stdout: Option<String>,
+    /// Standard error of the program, after cargo's `Running` line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub program_stderr: Option<String>,
+}
+
+impl From<CargoCommandJsonResult> for CargoRunResult {
+    fn from(mut command: CargoCommandJsonResult) -> Self {
+        let Some((cargo_stderr, program_stderr)) = split_stderr(&command.stderr) else {
+            return CargoRunResult {
+                command,
+                program_stdout: None,
+                program_stderr: None,
+            };
+        };
+        command.stderr = cargo_stderr;
+        CargoRunResult {
+            program_stdout: Some(std::mem::take(&mut command.stdout)),
+            program_stderr: Some(program_stderr),
+            command,
+        }
+    }
+}
+
+/// The program's output is cut in the middle, like cargo's
+impl ToolResponse for CargoRunResult {
+    fn command_mut(&mut self) -> &mut CargoCommandJsonResult {
+        &mut self.command
+    }
+
+    fn trim_parts(&mut self, limit: usize, truncation: &mut Truncation) {
+        if let Some(stdout) = &mut self.program_stdout {
+            truncation.elided_stdout_bytes += truncate_middle(stdout, limit / 2);
+        }
+        if let Some(stderr) = &mut self.program_stderr {
+            truncation.elided_stderr_bytes += truncate_middle(stderr, limit / 2);
+        }
+    }
+}
+
+/// Split stderr at cargo's `Running `target/debug/app`` line, the last thing cargo prints
+/// before the program takes over. That is the first `Running` line after `Finished`: with
+/// `-v`, cargo also reports each rustc and build script it runs during the build. Returns
+/// `None` if cargo never got that far.
+fn split_stderr(stderr: &str) -> Option<(String, String)> {
+    let mut lines = stderr.lines();
+    let mut cargo = Vec::new();
+    let mut finished = false;
+    for line in lines.by_ref() {
+        cargo.push(line);
+        let status = line.trim_start();
+        finished |= status.starts_with("Finished ");
+        if finished && status.starts_with("Running `") {
+            let program = lines.collect::<Vec<_>>().join("\n");
+            return Some((cargo.join("\n"), program));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_stderr() {
+        let stderr = "   Compiling app v0.1.0 (/p)\n    Finished `dev` profile\n     Running `target/debug/app`\nRunning `x` from the program\nthread 'main' panicked";
+        assert_eq!(
+            split_stderr(stderr),
+            Some((
+                "   Compiling app v0.1.0 (/p)\n    Finished `dev` profile\n     Running `target/debug/app`".to_string(),
+                "Running `x` from the program\nthread 'main' panicked".to_string()
+            ))
+        );
+        assert_eq!(
+            split_stderr("error: could not compile `app` (bin \"app\") due to 1 previous error"),
+            None
+        );
+
+        let verbose = "   Compiling app v0.1.0 (/p)\n     Running `/p/target/debug/build/app-1/build-script-build`\n     Running `rustc --crate-name app src/main.rs`\n    Finished `dev` profile\n     Running `target/debug/app`\nhello";
+        let (cargo, program) = split_stderr(verbose).unwrap();
+        assert!(cargo.ends_with("Running `target/debug/app`"));
+        assert_eq!(program, "hello");
+    }
+}
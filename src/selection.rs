@@ -0,0 +1,247 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::{PackageInfo, WorkspaceInfo};
+
+/// Which packages, targets, features and profile a build tool works on. Everything left unset
+/// falls back to cargo's defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct BuildSelection {
+    /// Packages to build (`-p`); may use the `*` and `?` wildcards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<Vec<String>>,
+    /// Build every workspace member (`--workspace`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workspace: Option<bool>,
+    /// Workspace members to leave out; requires `workspace`, and may use wildcards
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude: Option<Vec<String>>,
+    /// Only the library target (`--lib`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lib: Option<bool>,
+    /// All binary targets (`--bins`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bins: Option<bool>,
+    /// All integration test targets (`--tests`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tests: Option<bool>,
+    /// All example targets (`--examples`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub examples: Option<bool>,
+    /// Every target, including tests, examples and benches (`--all-targets`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub all_targets: Option<bool>,
+    /// Features to enable; use `package/feature` for a feature of a specific package
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub all_features: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_default_features: Option<bool>,
+    /// Target triple to build for, e.g. `wasm32-unknown-unknown`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    /// Build profile (`--profile`), e.g. `release` or a custom profile
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Shorthand for the release profile (`--release`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release: Option<bool>,
+}
+
+impl BuildSelection {
+    /// Whether any field is set, i.e. whether there is anything to validate or pass on
+    pub fn is_empty(&self) -> bool {
+        self.args().is_empty()
+    }
+
+    /// Arguments to pass to cargo
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        for package in self.package.iter().flatten() {
+            args.extend(["-p".to_string(), package.clone()]);
+        }
+        let flags = [
+            (self.workspace, "--workspace"),
+            (self.lib, "--lib"),
+            (self.bins, "--bins"),
+            (self.tests, "--tests"),
+            (self.examples, "--examples"),
+            (self.all_targets, "--all-targets"),
+            (self.all_features, "--all-features"),
+            (self.no_default_features, "--no-default-features"),
+            (self.release, "--release"),
+        ];
+        for (enabled, flag) in flags {
+            if enabled.unwrap_or(false) {
+                args.push(flag.to_string());
+            }
+        }
+        for package in self.exclude.iter().flatten() {
+            args.extend(["--exclude".to_string(), package.clone()]);
+        }
+        if let Some(features) = &self.features
+            && !features.is_empty()
+        {
+            args.extend(["--features".to_string(), features.join(",")]);
+        }
+        for (flag, value) in [("--target", &self.target), ("--profile", &self.profile)] {
+            if let Some(value) = value {
+                args.extend([flag.to_string(), value.clone()]);
+            }
+        }
+        args
+    }
+
+    /// Check the selection against the workspace, so that a typo in a feature name gets a
+    /// clear error instead of a cargo failure halfway through a build. Only the features of
+    /// selected workspace members are checked; cargo knows best about other packages, such as
+    /// dependencies.
+    pub fn validate(&self, workspace: &WorkspaceInfo) -> anyhow::Result<()> {
+        if self.release.unwrap_or(false) && self.profile.is_some() {
+            anyhow::bail!("`release` and `profile` cannot be used together");
+        }
+        if self.exclude.is_some() && !self.workspace.unwrap_or(false) {
+            anyhow::bail!("`exclude` can only be used together with `workspace`");
+        }
+
+        let mut selected: Vec<&PackageInfo> = Vec::new();
+        // Whether a package outside the workspace was selected, whose features are unknown
+        let mut outside = false;
+        for spec in self.package.iter().flatten() {
+            let before = selected.len();
+            selected.extend(workspace.members.iter().filter(|p| matches(spec, &p.name)));
+            outside |= selected.len() == before;
+        }
+        if self.workspace.unwrap_or(false) || self.package.is_none() {
+            let excluded = |p: &PackageInfo| {
+                self.exclude
+                    .iter()
+                    .flatten()
+                    .any(|spec| matches(spec, &p.name))
+            };
+            selected = workspace.members.iter().filter(|p| !excluded(p)).collect();
+        }
+
+        for feature in self.features.iter().flatten() {
+            let declared = match feature.split_once('/') {
+                // Otherwise `package` is a dependency, whose features cargo checks
+                Some((package, feature)) => selected
+                    .iter()
+                    .find(|p| p.name == package)
+                    .is_none_or(|p| p.features.contains_key(feature)),
+                None => outside || selected.iter().any(|p| p.features.contains_key(feature)),
+            };
+            if !declared {
+                anyhow::bail!("feature `{feature}` is not declared by the selected packages");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether the package `name` matches `spec`, which may pin a version (`name@1.0.0`) and use
+/// the `*` and `?` wildcards
+fn matches(spec: &str, name: &str) -> bool {
+    fn glob(pattern: &[u8], name: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some((b'*', rest)) => (0..=name.len()).any(|i| glob(rest, &name[i..])),
+            Some((b'?', rest)) => !name.is_empty() && glob(rest, &name[1..]),
+            Some((c, rest)) => name.first() == Some(c) && glob(rest, &name[1..]),
+        }
+    }
+    let pattern = spec.split('@').next().unwrap_or(spec);
+    glob(pattern.as_bytes(), name.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    fn workspace() -> WorkspaceInfo {
+        WorkspaceInfo {
+            workspace_root: "/p".to_string(),
+            members: vec![PackageInfo {
+                name: "app".to_string(),
+                version: "0.1.0".to_string(),
+                manifest_path: "/p/Cargo.toml".to_string(),
+                targets: vec![],
+                features: BTreeMap::from([("fast".to_string(), vec![])]),
+                dependencies: vec![],
+            }],
+            resolve: None,
+        }
+    }
+
+    #[test]
+    fn test_args() {
+        let selection = BuildSelection {
+            package: Some(vec!["app".to_string()]),
+            all_targets: Some(true),
+            features: Some(vec!["fast".to_string(), "app/fast".to_string()]),
+            release: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            selection.args(),
+            [
+                "-p",
+                "app",
+                "--all-targets",
+                "--release",
+                "--features",
+                "fast,app/fast"
+            ]
+        );
+        selection.validate(&workspace()).unwrap();
+        assert!(BuildSelection::default().is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let validate = |selection: BuildSelection| {
+            selection
+                .validate(&workspace())
+                .map_err(|err| err.to_string())
+        };
+
+        // Left for cargo to report
+        let dependency = BuildSelection {
+            package: Some(vec!["serde".to_string()]),
+            features: Some(vec!["derive".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(validate(dependency), Ok(()));
+        let dependency_feature = BuildSelection {
+            features: Some(vec!["serde/derive".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(validate(dependency_feature), Ok(()));
+
+        let unknown_feature = BuildSelection {
+            features: Some(vec!["slow".to_string()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            validate(unknown_feature),
+            Err("feature `slow` is not declared by the selected packages".to_string())
+        );
+        let unknown_member_feature = BuildSelection {
+            package: Some(vec!["a*".to_string()]),
+            features: Some(vec!["app/slow".to_string()]),
+            ..Default::default()
+        };
+        assert!(validate(unknown_member_feature).is_err());
+
+        let excluded = BuildSelection {
+            workspace: Some(true),
+            exclude: Some(vec!["ap?".to_string()]),
+            features: Some(vec!["fast".to_string()]),
+            ..Default::default()
+        };
+        assert!(validate(excluded).is_err());
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use sacp::schema::SessionId;
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+use crate::baseline::{Baseline, BaselineCommand};
+use crate::cancellation::ToolCalls;
+use crate::config::{Config, ConfigSources};
+use crate::diagnostic::{Diagnostic, same_diagnostics};
+use crate::results::ResultStore;
+
+/// What the proxy should do after an automatic check failed
+#[derive(Debug, PartialEq, Eq)]
+pub enum AutoCheckOutcome {
+    /// Prompt the agent to fix the errors
+    FollowUp,
+    /// The agent's last follow-up left exactly the same errors behind
+    NoProgress,
+    /// The agent has already been prompted the maximum number of times in a row
+    BudgetExhausted,
+}
+
+/// Progress through consecutive automatic follow-up prompts
+#[derive(Default)]
+struct AutoCheckState {
+    followups: usize,
+    /// The stage that failed last time and the diagnostics it reported
+    last_failure: Option<(String, Vec<Diagnostic>)>,
+}
+
+/// State the proxy keeps for a single ACP session
+pub struct SessionState {
+    /// Assigned once the agent has created the session
+    session_id: OnceLock<SessionId>,
+    /// Whether the progress of cargo commands is shown to the client as tool calls
+    progress_updates: bool,
+    /// Whether tools that change the workspace ask the user before they run
+    permission_prompts: bool,
+    /// Where the session's configuration is read from
+    config_sources: Arc<ConfigSources>,
+    /// Working directory the client gave in `session/new`, or the configured `cwd` relative
+    /// to it
+    session_cwd: Option<String>,
+    /// Directory set by the agent through `set_cwd`, taking precedence over `session_cwd`
+    cwd_override: RwLock<Option<String>>,
+    /// Toolchain set by the agent through `set_toolchain`
+    toolchain: RwLock<Option<String>>,
+    /// Rust files the agent edited since the last automatic check
+    unchecked_rs_files: Mutex<BTreeSet<PathBuf>>,
+    auto_check: Mutex<AutoCheckState>,
+    /// Cancelled (and replaced) when the client cancels the current turn
+    cancellation: Mutex<CancellationToken>,
+    /// Complete results of recent cargo commands, for `get_cargo_result`
+    results: ResultStore,
+    /// Diagnostics that were there before the agent started, per command
+    baselines: Mutex<HashMap<BaselineCommand, Baseline>>,
+    /// Tools the user chose to always allow for the rest of the session
+    always_allowed: Mutex<BTreeSet<String>>,
+    /// Tool calls the MCP client can cancel one by one
+    tool_calls: ToolCalls,
+}
+
+impl SessionState {
+    pub fn new(session_cwd: Option<String>) -> Self {
+        Self {
+            session_id: OnceLock::new(),
+            progress_updates: true,
+            permission_prompts: true,
+            config_sources: Arc::new(ConfigSources::default()),
+            session_cwd,
+            cwd_override: RwLock::new(None),
+            toolchain: RwLock::new(None),
+            unchecked_rs_files: Mutex::new(BTreeSet::new()),
+            auto_check: Mutex::new(AutoCheckState::default()),
+            cancellation: Mutex::new(CancellationToken::new()),
+            results: ResultStore::default(),
+            baselines: Mutex::new(HashMap::new()),
+            always_allowed: Mutex::new(BTreeSet::new()),
+            tool_calls: ToolCalls::default(),
+        }
+    }
+
+    /// Enable or disable mirroring cargo progress to the client
+    pub fn with_progress_updates(mut self, enabled: bool) -> Self {
+        self.progress_updates = enabled;
+        self
+    }
+
+    pub fn progress_updates(&self) -> bool {
+        self.progress_updates
+    }
+
+    /// Enable or disable asking the user before tools that change the workspace run
+    pub fn with_permission_prompts(mut self, enabled: bool) -> Self {
+        self.permission_prompts = enabled;
+        self
+    }
+
+    pub fn permission_prompts(&self) -> bool {
+        self.permission_prompts
+    }
+
+    /// Read the configuration from `sources`, and move the session to the configured `cwd`
+    /// if there is one
+    pub fn with_config_sources(mut self, sources: Arc<ConfigSources>) -> Self {
+        self.config_sources = sources;
+        let base = self.session_cwd.as_deref().map(Path::new);
+        match self.config(base) {
+            Ok(Config { cwd: Some(cwd), .. }) => {
+                let cwd = match base {
+                    Some(base) => base.join(cwd),
+                    None => cwd,
+                };
+                self.session_cwd = Some(cwd.display().to_string());
+            }
+            Ok(_) => {}
+            // Reported again by the first tool call that needs the configuration
+            Err(err) => tracing::warn!(?err, "failed to load the configuration"),
+        }
+        self
+    }
+
+    /// The configuration that applies in `cwd`
+    pub fn config(&self, cwd: Option<&Path>) -> anyhow::Result<Config> {
+        self.config_sources.load(cwd)
+    }
+
+    /// The configuration without the project's file, for settings the agent must not be able
+    /// to change by editing the project
+    pub fn user_config(&self) -> anyhow::Result<Config> {
+        self.config_sources.load(None)
+    }
+
+    pub fn set_session_id(&self, session_id: SessionId) {
+        // The id never changes, so a second call has nothing to update
+        let _ = self.session_id.set(session_id);
+    }
+
+    pub fn session_id(&self) -> Option<SessionId> {
+        self.session_id.get().cloned()
+    }
+
+    /// The directory the session started in, before any `set_cwd`
+    pub fn session_cwd(&self) -> Option<&str> {
+        self.session_cwd.as_deref()
+    }
+
+    /// The directory commands run in when the tool call does not provide one
+    pub async fn cwd(&self) -> Option<String> {
+        self.cwd_override
+            .read()
+            .await
+            .clone()
+            .or_else(|| self.session_cwd.clone())
+    }
+
+    /// Override the session directory; `None` reverts to the one from `session/new`
+    pub async fn set_cwd(&self, cwd: Option<String>) {
+        *self.cwd_override.write().await = cwd;
+    }
+
+    /// Pick `explicit` if given, otherwise fall back to the session directory
+    pub async fn resolve_cwd(&self, explicit: Option<String>) -> Option<String> {
+        match explicit {
+            Some(cwd) => Some(cwd),
+            None => self.cwd().await,
+        }
+    }
+
+    pub fn results(&self) -> &ResultStore {
+        &self.results
+    }
+
+    /// The baseline diagnostics of `command`, if it was captured with the same `args`
+    pub fn baseline(&self, command: BaselineCommand, args: &[&str]) -> Option<Vec<Diagnostic>> {
+        self.baselines
+            .lock()
+            .expect("not poisoned")
+            .get(&command)
+            .filter(|baseline| baseline.args == args)
+            .map(|baseline| baseline.diagnostics.clone())
+    }
+
+    pub fn set_baseline(&self, command: BaselineCommand, baseline: Baseline) {
+        self.baselines
+            .lock()
+            .expect("not poisoned")
+            .insert(command, baseline);
+    }
+
+    /// Whether the user chose to always allow `tool` in this session
+    pub fn is_always_allowed(&self, tool: &str) -> bool {
+        self.always_allowed
+            .lock()
+            .expect("not poisoned")
+            .contains(tool)
+    }
+
+    /// Stop asking the user before `tool` runs, for the rest of the session
+    pub fn always_allow(&self, tool: &str) {
+        self.always_allowed
+            .lock()
+            .expect("not poisoned")
+            .insert(tool.to_string());
+    }
+
+    /// The toolchain commands run with when the tool call does not name one
+    pub async fn toolchain(&self) -> Option<String> {
+        self.toolchain.read().await.clone()
+    }
+
+    /// Set the default toolchain; `None` goes back to the one rustup picks for the directory
+    pub async fn set_toolchain(&self, toolchain: Option<String>) {
+        *self.toolchain.write().await = toolchain;
+    }
+
+    /// Pick `explicit` if given, otherwise fall back to the session toolchain
+    pub async fn resolve_toolchain(&self, explicit: Option<String>) -> Option<String> {
+        match explicit {
+            Some(toolchain) => Some(toolchain),
+            None => self.toolchain().await,
+        }
+    }
+
+    /// Remember that the agent touched these Rust files
+    pub fn record_rs_edits(&self, paths: impl IntoIterator<Item = PathBuf>) {
+        self.unchecked_rs_files
+            .lock()
+            .expect("not poisoned")
+            .extend(paths);
+    }
+
+    /// Take the Rust files edited since the last call, leaving the set empty
+    pub fn take_unchecked_rs_files(&self) -> BTreeSet<PathBuf> {
+        std::mem::take(&mut *self.unchecked_rs_files.lock().expect("not poisoned"))
+    }
+
+    pub(crate) fn tool_calls(&self) -> &ToolCalls {
+        &self.tool_calls
+    }
+
+    /// Token that fires if the client cancels the turn that is running now
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.lock().expect("not poisoned").clone()
+    }
+
+    /// Kill the cargo commands started during the current turn
+    pub fn cancel_running(&self) {
+        std::mem::take(&mut *self.cancellation.lock().expect("not poisoned")).cancel();
+    }
+
+    /// Start counting follow-ups from zero, e.g. because the user sent a new prompt
+    pub fn reset_auto_check(&self) {
+        *self.auto_check.lock().expect("not poisoned") = AutoCheckState::default();
+    }
+
+    /// Record a failed automatic check and decide whether to prompt the agent again
+    pub fn record_auto_check_failure(
+        &self,
+        stage: &str,
+        diagnostics: &[Diagnostic],
+        max_followups: usize,
+    ) -> AutoCheckOutcome {
+        let mut state = self.auto_check.lock().expect("not poisoned");
+        // Stages without diagnostics (e.g. `cargo fmt`) only stop once the budget runs out
+        let no_progress = !diagnostics.is_empty()
+            && state
+                .last_failure
+                .as_ref()
+                .is_some_and(|(last_stage, last)| {
+                    last_stage == stage && same_diagnostics(last, diagnostics)
+                });
+        state.last_failure = Some((stage.to_string(), diagnostics.to_vec()));
+
+        if no_progress {
+            AutoCheckOutcome::NoProgress
+        } else if state.followups >= max_followups {
+            AutoCheckOutcome::BudgetExhausted
+        } else {
+            state.followups += 1;
+            AutoCheckOutcome::FollowUp
+        }
+    }
+}
+
+/// All sessions created through the proxy, keyed by their id
+#[derive(Clone, Default)]
+pub struct Sessions {
+    sessions: Arc<Mutex<HashMap<SessionId, Arc<SessionState>>>>,
+}
+
+impl Sessions {
+    pub fn insert(&self, session_id: SessionId, state: Arc<SessionState>) {
+        self.sessions
+            .lock()
+            .expect("not poisoned")
+            .insert(session_id, state);
+    }
+
+    pub fn get(&self, session_id: &SessionId) -> Option<Arc<SessionState>> {
+        self.sessions
+            .lock()
+            .expect("not poisoned")
+            .get(session_id)
+            .cloned()
+    }
+
+    /// Forget every session, e.g. because the connection closed, and cancel the commands they
+    /// are running. ACP has no message that ends a single session, so this is the only point
+    /// at which a session's state can go.
+    pub fn clear(&self) {
+        let sessions = std::mem::take(&mut *self.sessions.lock().expect("not poisoned"));
+        for session in sessions.into_values() {
+            session.cancel_running();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic::DiagnosticLevel;
+
+    #[tokio::test]
+    async fn test_cwd_override() {
+        let session = SessionState::new(Some("/project".to_string()));
+        assert_eq!(session.cwd().await.as_deref(), Some("/project"));
+
+        session.set_cwd(Some("/project/crate".to_string())).await;
+        assert_eq!(session.cwd().await.as_deref(), Some("/project/crate"));
+        assert_eq!(
+            session
+                .resolve_cwd(Some("/other".to_string()))
+                .await
+                .as_deref(),
+            Some("/other")
+        );
+
+        session.set_cwd(None).await;
+        assert_eq!(session.resolve_cwd(None).await.as_deref(), Some("/project"));
+    }
+
+    #[test]
+    fn test_unchecked_rs_files_are_per_session() {
+        let sessions = Sessions::default();
+        let a = Arc::new(SessionState::new(None));
+        let b = Arc::new(SessionState::new(None));
+        sessions.insert(SessionId::new("a"), a.clone());
+        sessions.insert(SessionId::new("b"), b.clone());
+
+        sessions
+            .get(&SessionId::new("a"))
+            .unwrap()
+            .record_rs_edits([PathBuf::from("src/lib.rs"), PathBuf::from("src/lib.rs")]);
+
+        assert!(b.take_unchecked_rs_files().is_empty());
+        assert_eq!(
+            a.take_unchecked_rs_files(),
+            BTreeSet::from([PathBuf::from("src/lib.rs")])
+        );
+        assert!(a.take_unchecked_rs_files().is_empty());
+    }
+
+    #[test]
+    fn test_clear() {
+        let sessions = Sessions::default();
+        let session = Arc::new(SessionState::new(None));
+        let running = session.cancellation_token();
+        sessions.insert(SessionId::new("a"), session.clone());
+
+        sessions.clear();
+        assert!(sessions.get(&SessionId::new("a")).is_none());
+        assert!(running.is_cancelled());
+        assert_eq!(Arc::strong_count(&session), 1);
+    }
+
+    #[test]
+    fn test_cancel_running() {
+        let session = SessionState::new(None);
+        let running = session.cancellation_token();
+        session.cancel_running();
+        assert!(running.is_cancelled());
+        assert!(!session.cancellation_token().is_cancelled());
+    }
+
+    #[test]
+    fn test_auto_check_budget() {
+        let session = SessionState::new(None);
+        let outcomes: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|m| {
+                session.record_auto_check_failure(
+                    "cargo check",
+                    &[Diagnostic::for_test(DiagnosticLevel::Error, m)],
+                    2,
+                )
+            })
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                AutoCheckOutcome::FollowUp,
+                AutoCheckOutcome::FollowUp,
+                AutoCheckOutcome::BudgetExhausted
+            ]
+        );
+
+        session.reset_auto_check();
+        assert_eq!(
+            session.record_auto_check_failure(
+                "cargo check",
+                &[Diagnostic::for_test(DiagnosticLevel::Error, "c")],
+                2
+            ),
+            AutoCheckOutcome::FollowUp
+        );
+    }
+
+    #[test]
+    fn test_auto_check_no_progress() {
+        let session = SessionState::new(None);
+        assert_eq!(
+            session.record_auto_check_failure(
+                "cargo check",
+                &[
+                    Diagnostic::for_test(DiagnosticLevel::Error, "a"),
+                    Diagnostic::for_test(DiagnosticLevel::Error, "b")
+                ],
+                5
+            ),
+            AutoCheckOutcome::FollowUp
+        );
+        assert_eq!(
+            session.record_auto_check_failure(
+                "cargo check",
+                &[
+                    Diagnostic::for_test(DiagnosticLevel::Error, "b"),
+                    Diagnostic::for_test(DiagnosticLevel::Error, "a")
+                ],
+                5
+            ),
+            AutoCheckOutcome::NoProgress
+        );
+        assert_eq!(
+            session.record_auto_check_failure(
+                "cargo clippy",
+                &[
+                    Diagnostic::for_test(DiagnosticLevel::Error, "b"),
+                    Diagnostic::for_test(DiagnosticLevel::Error, "a")
+                ],
+                5
+            ),
+            AutoCheckOutcome::FollowUp
+        );
+    }
+}
@@ -0,0 +1,391 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::diagnostic::{Applicability, Suggestion};
+use crate::results::StoredResult;
+
+/// A suggestion that was applied to a source file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct AppliedEdit {
+    pub file: String,
+    pub line: usize,
+    /// Lint or error code of the diagnostic the suggestion belongs to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    /// What the suggestion does, e.g. `remove the unused import`
+    pub message: String,
+    /// The text that was replaced
+    pub original: String,
+    pub replacement: String,
+}
+
+/// A suggestion that was left alone, and why
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct RejectedSuggestion {
+    pub file: String,
+    pub line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    pub message: String,
+    pub reason: String,
+}
+
+/// Result of `cargo_apply_suggestions`
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct ApplySuggestionsResult {
+    /// The `result_id` of the check or clippy run the suggestions were taken from
+    pub result_id: u64,
+    pub applied: Vec<AppliedEdit>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub rejected: Vec<RejectedSuggestion>,
+}
+
+/// Which suggestions to apply
+#[derive(Debug, Default)]
+pub struct SuggestionFilter<'a> {
+    /// Only suggestions for diagnostics with one of these codes
+    pub codes: Option<&'a [String]>,
+    /// Only suggestions that edit nothing but this file
+    pub file: Option<&'a str>,
+}
+
+/// The spans of one suggestion, which are applied together or not at all
+struct Group<'a> {
+    code: Option<&'a str>,
+    message: &'a str,
+    edits: Vec<&'a Suggestion>,
+}
+
+impl Group<'_> {
+    fn reject(&self, reason: impl Into<String>) -> RejectedSuggestion {
+        RejectedSuggestion {
+            file: self.edits[0].span.file.clone(),
+            line: self.edits[0].span.line_start,
+            code: self.code.map(str::to_string),
+            message: self.message.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    fn same_edits(&self, other: &Group) -> bool {
+        self.edits.len() == other.edits.len()
+            && self.edits.iter().zip(&other.edits).all(|(a, b)| {
+                a.span.file == b.span.file
+                    && (a.span.byte_start, a.span.byte_end) == (b.span.byte_start, b.span.byte_end)
+                    && a.replacement == b.replacement
+            })
+    }
+}
+
+/// The cargo subcommand of a result's command line, e.g. `clippy` for
+/// `cargo +nightly clippy --message-format json`
+pub fn subcommand(command: &str) -> Option<&str> {
+    command
+        .split_whitespace()
+        .skip(1)
+        .find(|word| !word.starts_with('+'))
+}
+
+/// Apply the machine-applicable suggestions of a stored result to the files under `root`, the
+/// workspace root that rustc's paths are relative to. A suggestion is rejected if its file
+/// is not under `root`, if the file changed since the run, or if it overlaps a suggestion that
+/// was accepted before it.
+pub fn apply(
+    source: &StoredResult,
+    root: &Path,
+    filter: &SuggestionFilter,
+) -> anyhow::Result<ApplySuggestionsResult> {
+    let root = &root
+        .canonicalize()
+        .with_context(|| format!("failed to read {}", root.display()))?;
+    let mut groups: Vec<Group> = Vec::new();
+    for diagnostic in &source.result.diagnostics {
+        if let Some(codes) = filter.codes
+            && !diagnostic
+                .code
+                .as_ref()
+                .is_some_and(|code| codes.contains(code))
+        {
+            continue;
+        }
+        let mut by_message: BTreeMap<&str, Vec<&Suggestion>> = BTreeMap::new();
+        for suggestion in &diagnostic.suggestions {
+            if suggestion.applicability == Applicability::MachineApplicable {
+                by_message
+                    .entry(&suggestion.message)
+                    .or_default()
+                    .push(suggestion);
+            }
+        }
+        for (message, edits) in by_message {
+            let group = Group {
+                code: diagnostic.code.as_deref(),
+                message,
+                edits,
+            };
+            let in_file = |file: &str| {
+                group
+                    .edits
+                    .iter()
+                    .all(|e| e.span.file == file || e.span.file.ends_with(&format!("/{file}")))
+            };
+            // The same suggestion comes up once per target that compiles the file
+            if filter.file.is_none_or(in_file) && !groups.iter().any(|g| g.same_edits(&group)) {
+                groups.push(group);
+            }
+        }
+    }
+
+    let mut contents: BTreeMap<&str, Option<String>> = BTreeMap::new();
+    // Accepted edits per file, as (start, end, suggestion, group index)
+    let mut accepted: BTreeMap<&str, Vec<(usize, usize, &Suggestion, usize)>> = BTreeMap::new();
+    let mut rejected = Vec::new();
+    'groups: for (index, group) in groups.iter().enumerate() {
+        for edit in &group.edits {
+            let span = &edit.span;
+            if !inside(root, &span.file) {
+                rejected.push(group.reject("the file is outside the workspace"));
+                continue 'groups;
+            }
+            let text = contents
+                .entry(&span.file)
+                .or_insert_with(|| read_unchanged(&root.join(&span.file), source.finished_at));
+            let Some(text) = text else {
+                rejected.push(group.reject("the file changed since the run; check it again"));
+                continue 'groups;
+            };
+            if text.get(span.byte_start..span.byte_end).is_none()
+                || !at_position(text, span.byte_start, span.line_start, span.column_start)
+            {
+                rejected.push(group.reject("the file changed since the run; check it again"));
+                continue 'groups;
+            }
+            let overlaps = accepted.get(span.file.as_str()).is_some_and(|edits| {
+                edits.iter().any(|(start, end, _, _)| {
+                    span.byte_start < *end && *start < span.byte_end || span.byte_start == *start
+                })
+            });
+            if overlaps {
+                rejected.push(group.reject("overlaps another suggestion that was applied"));
+                continue 'groups;
+            }
+        }
+        for edit in &group.edits {
+            let span = &edit.span;
+            accepted.entry(&span.file).or_default().push((
+                span.byte_start,
+                span.byte_end,
+                edit,
+                index,
+            ));
+        }
+    }
+
+    let mut applied = Vec::new();
+    for (file, mut edits) in accepted {
+        let mut text = contents
+            .remove(file)
+            .flatten()
+            .expect("accepted edits are for files that were read");
+        edits.sort_by_key(|(start, ..)| *start);
+        for (start, end, edit, index) in &edits {
+            applied.push(AppliedEdit {
+                file: file.to_string(),
+                line: edit.span.line_start,
+                code: groups[*index].code.map(str::to_string),
+                message: groups[*index].message.to_string(),
+                original: text[*start..*end].to_string(),
+                replacement: edit.replacement.clone(),
+            });
+        }
+        // Back to front, so the offsets of the remaining edits stay valid
+        for (start, end, edit, _) in edits.iter().rev() {
+            text.replace_range(*start..*end, &edit.replacement);
+        }
+        let path = root.join(file);
+        std::fs::write(&path, text)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+
+    Ok(ApplySuggestionsResult {
+        result_id: source.id,
+        applied,
+        rejected,
+    })
+}
+
+/// The files `result` edited, as absolute paths
+pub fn edited_files(result: &ApplySuggestionsResult, root: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<_> = result.applied.iter().map(|e| root.join(&e.file)).collect();
+    files.dedup();
+    files
+}
+
+/// Whether `file`, relative to `root` or absolute, resolves to a file under `root`. Files that
+/// do not exist are outside, as are paths that leave `root` through `..` or a symlink.
+fn inside(root: &Path, file: &str) -> bool {
+    root.join(file)
+        .canonicalize()
+        .is_ok_and(|path| path.starts_with(root))
+}
+
+/// The contents of `path`, unless it was modified after `since` or cannot be read
+fn read_unchanged(path: &Path, since: SystemTime) -> Option<String> {
+    let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    (modified <= since)
+        .then(|| std::fs::read_to_string(path).ok())
+        .flatten()
+}
+
+/// Whether `byte` sits at the 1-based `line` and `column` (counted in characters) that rustc
+/// reported for it; if not, the file was edited after the run
+fn at_position(text: &str, byte: usize, line: usize, column: usize) -> bool {
+    let Some(before) = text.get(..byte) else {
+        return false;
+    };
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    before.matches('\n').count() + 1 == line && before[line_start..].chars().count() + 1 == column
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo_command::CargoCommandJsonResult;
+    use crate::diagnostic::{Diagnostic, DiagnosticLevel, DiagnosticSpan};
+    use std::time::Duration;
+
+    fn suggestion(text: &str, find: &str, replacement: &str, message: &str) -> Suggestion {
+        let byte_start = text.find(find).unwrap();
+        let before = &text[..byte_start];
+        let line = before.matches('\n').count() + 1;
+        let column = byte_start - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        Suggestion {
+            message: message.to_string(),
+            span: DiagnosticSpan {
+                file: "src/lib.rs".to_string(),
+                line_start: line,
+                line_end: line,
+                column_start: column,
+                column_end: column + find.len(),
+                byte_start,
+                byte_end: byte_start + find.len(),
+                is_primary: true,
+                label: None,
+            },
+            replacement: replacement.to_string(),
+            applicability: Applicability::MachineApplicable,
+        }
+    }
+
+    fn diagnostic(code: &str, suggestions: Vec<Suggestion>) -> Diagnostic {
+        Diagnostic {
+            suggestions,
+            ..Diagnostic::for_test(DiagnosticLevel::Warning, code).with_code(code)
+        }
+    }
+
+    fn stored(diagnostics: Vec<Diagnostic>) -> StoredResult {
+        StoredResult {
+            id: 1,
+            result: CargoCommandJsonResult::for_test(
+                "cargo clippy --message-format json",
+                diagnostics,
+            ),
+            // Well in the past, so that any write counts as a change after the run, however
+            // coarse the file system's timestamps
+            finished_at: SystemTime::now() - Duration::from_secs(5),
+            workspace_root: None,
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let root = std::env::temp_dir().join(format!("apply-suggestions-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        let text = "fn f() {\n    let x = 1;\n    let y = 2;\n}\n";
+        std::fs::write(root.join("src/lib.rs"), text).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(root.join("src/lib.rs"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(10))
+            .unwrap();
+
+        let diagnostics = vec![
+            diagnostic(
+                "unused_variables",
+                vec![suggestion(text, "x", "_x", "prefix it with an underscore")],
+            ),
+            // The same suggestion again, as for the test target
+            diagnostic(
+                "unused_variables",
+                vec![suggestion(text, "x", "_x", "prefix it with an underscore")],
+            ),
+            diagnostic(
+                "other",
+                vec![suggestion(text, "x = 1", "x = 3", "overlapping")],
+            ),
+            diagnostic(
+                "unused_variables",
+                vec![suggestion(text, "y", "_y", "prefix it with an underscore")],
+            ),
+        ];
+        let source = stored(diagnostics);
+
+        let applied = apply(&source, &root, &SuggestionFilter::default()).unwrap();
+        assert_eq!(applied.applied.len(), 2);
+        assert_eq!(applied.applied[1].original, "y");
+        assert_eq!(applied.rejected.len(), 1);
+        assert_eq!(applied.rejected[0].code.as_deref(), Some("other"));
+        assert_eq!(
+            std::fs::read_to_string(root.join("src/lib.rs")).unwrap(),
+            "fn f() {\n    let _x = 1;\n    let _y = 2;\n}\n"
+        );
+
+        // The file has changed, so the same suggestions no longer line up
+        let again = apply(&source, &root, &SuggestionFilter::default()).unwrap();
+        assert!(again.applied.is_empty());
+        assert_eq!(again.rejected.len(), 3);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(subcommand(&source.result.command), Some("clippy"));
+        assert_eq!(subcommand("cargo +nightly check"), Some("check"));
+    }
+
+    #[test]
+    fn test_apply_outside_workspace() {
+        let dir = std::env::temp_dir().join(format!("apply-outside-{}", std::process::id()));
+        let root = dir.join("workspace");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("dep/src")).unwrap();
+        let text = "fn f() {\n    let x = 1;\n}\n";
+        let dep = dir.join("dep/src/lib.rs");
+        std::fs::write(&dep, text).unwrap();
+
+        let mut relative = suggestion(text, "x", "_x", "prefix it with an underscore");
+        relative.span.file = "../dep/src/lib.rs".to_string();
+        let mut absolute = suggestion(text, "x", "_x", "prefix it with an underscore");
+        absolute.span.file = dep.display().to_string();
+        let source = stored(vec![
+            diagnostic("unused_variables", vec![relative]),
+            diagnostic("unused_variables", vec![absolute]),
+        ]);
+
+        let applied = apply(&source, &root, &SuggestionFilter::default()).unwrap();
+        assert!(applied.applied.is_empty());
+        assert_eq!(applied.rejected.len(), 2);
+        assert!(
+            applied
+                .rejected
+                .iter()
+                .all(|r| r.reason == "the file is outside the workspace")
+        );
+        assert_eq!(std::fs::read_to_string(&dep).unwrap(), text);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
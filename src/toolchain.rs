@@ -0,0 +1,80 @@
+use tokio::process::Command;
+
+/// Fail unless `toolchain` (e.g. `nightly`, `1.75.0` or `stable-x86_64-unknown-linux-gnu`)
+/// is installed, according to `rustup toolchain list`
+pub async fn ensure_installed(toolchain: &str) -> anyhow::Result<()> {
+    let output = Command::new("rustup")
+        .args(["toolchain", "list"])
+        .output()
+        .await
+        .map_err(|err| anyhow::anyhow!("selecting a toolchain requires rustup: {err}"))?;
+    let list = String::from_utf8_lossy(&output.stdout);
+    let installed = installed_toolchains(&list);
+    if installed.iter().any(|name| matches(name, toolchain)) {
+        return Ok(());
+    }
+    anyhow::bail!(
+        "toolchain `{toolchain}` is not installed; installed toolchains: {}. Install it with `rustup toolchain install {toolchain}`.",
+        installed.join(", ")
+    )
+}
+
+/// The version of rustc that cargo would use in `cwd`, e.g. `rustc 1.97.0-nightly (e50aa6fba 2026-05-19)`
+pub async fn rustc_version(toolchain: Option<&str>, cwd: Option<&str>) -> Option<String> {
+    let mut cmd = Command::new("rustc");
+    if let Some(toolchain) = toolchain {
+        cmd.arg(format!("+{toolchain}"));
+    }
+    cmd.arg("--version");
+    if let Some(cwd) = cwd {
+        cmd.current_dir(cwd);
+    }
+    let output = cmd.output().await.ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Toolchain names from `rustup toolchain list`, without annotations like `(default)`
+fn installed_toolchains(list: &str) -> Vec<&str> {
+    list.lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect()
+}
+
+/// Whether the installed toolchain `name` is what the user asked for: either the full name
+/// or the name without its host triple. `nightly` does not match `nightly-2024-01-01-<host>`.
+fn matches(name: &str, requested: &str) -> bool {
+    name == requested
+        || name
+            .strip_prefix(requested)
+            .and_then(|host| host.strip_prefix('-'))
+            .is_some_and(|host| !host.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_installed_toolchains() {
+        let list = indoc::indoc! {"
+            stable-x86_64-unknown-linux-gnu (active, default)
+            nightly-x86_64-unknown-linux-gnu
+            1.75.0-x86_64-unknown-linux-gnu
+            nightly-2024-01-01-x86_64-unknown-linux-gnu
+        "};
+        let installed = installed_toolchains(list);
+        assert_eq!(installed.len(), 4);
+        assert!(!matches(installed[3], "nightly"));
+        assert!(installed.iter().any(|name| matches(name, "nightly")));
+        assert!(installed.iter().any(|name| matches(name, "1.75.0")));
+        assert!(
+            installed
+                .iter()
+                .any(|name| matches(name, "stable-x86_64-unknown-linux-gnu"))
+        );
+        assert!(!installed.iter().any(|name| matches(name, "1.75")));
+    }
+}
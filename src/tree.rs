@@ -0,0 +1,427 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::cargo_command::{
+    CargoCommandJsonResult, ToolResponse, Truncation, json_len, keep_within,
+};
+
+/// At most this many paths are reported for a `why` query
+const MAX_PATHS: usize = 20;
+
+/// Question to ask `cargo tree`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TreeQuery {
+    /// What the package (or the whole workspace) depends on
+    #[default]
+    Deps,
+    /// How the package ends up in the build: paths from workspace members to it
+    Why,
+    /// Packages that are built in more than one version
+    Duplicates,
+}
+
+impl TreeQuery {
+    /// Arguments to pass to `cargo tree` for this query about `package`
+    pub fn args(self, package: Option<&str>) -> anyhow::Result<Vec<&str>> {
+        let mut args = vec!["--prefix", "depth"];
+        match (self, package) {
+            (TreeQuery::Deps, Some(package)) => args.extend(["-p", package]),
+            (TreeQuery::Deps, None) => {}
+            (TreeQuery::Why, Some(package)) => args.extend(["--invert", package]),
+            (TreeQuery::Why, None) => anyhow::bail!("a `why` query needs a `package`"),
+            (TreeQuery::Duplicates, package) => {
+                args.push("--duplicates");
+                args.extend(package.into_iter().flat_map(|p| ["-p", p]));
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// A node of the dependency graph
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct TreeNode {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Set for feature edges (`-e features`): the parent enables this feature of `name`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feature: Option<String>,
+    /// The node's dependencies were already listed earlier in the tree
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub deduplicated: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub children: Vec<TreeNode>,
+}
+
+impl TreeNode {
+    /// How the node is referred to in paths, e.g. `serde v1.0.0` or `serde feature "derive"`
+    fn label(&self) -> String {
+        match (&self.feature, &self.version) {
+            (Some(feature), _) => format!("{} feature \"{feature}\"", self.name),
+            (None, Some(version)) => format!("{} v{version}", self.name),
+            (None, None) => self.name.clone(),
+        }
+    }
+
+    /// Levels of children below the node
+    fn depth(&self) -> usize {
+        self.children
+            .iter()
+            .map(|c| c.depth() + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Drop the nodes more than `depth` levels below this one, and return how many there were
+    fn prune(&mut self, depth: usize) -> usize {
+        if depth == 0 {
+            let count = self.children.iter_mut().map(|c| c.prune(0) + 1).sum();
+            self.children.clear();
+            return count;
+        }
+        self.children.iter_mut().map(|c| c.prune(depth - 1)).sum()
+    }
+}
+
+/// A package that is built in several versions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct DuplicateGroup {
+    pub name: String,
+    pub versions: Vec<DuplicateVersion>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct DuplicateVersion {
+    pub version: String,
+    /// Packages that depend on this version directly
+    pub dependents: Vec<String>,
+}
+
+/// Result of `cargo tree`, with the tree parsed according to the query
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CargoTreeResult {
+    #[serde(flatten)]
+    pub command: CargoCommandJsonResult,
+    /// The dependency tree, for `deps` queries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tree: Option<Vec<TreeNode>>,
+    /// Paths from a workspace member to the package, for `why` queries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paths: Option<Vec<Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicates: Option<Vec<DuplicateGroup>>,
+}
+
+impl CargoTreeResult {
+    pub fn new(mut command: CargoCommandJsonResult, query: TreeQuery) -> Self {
+        let (mut tree, mut paths, mut duplicates) = (None, None, None);
+        if command.exit_code == Some(0) {
+            let roots = parse_tree(&std::mem::take(&mut command.stdout));
+            match query {
+                TreeQuery::Deps => tree = Some(roots),
+                TreeQuery::Why => paths = Some(paths_to_roots(&roots)),
+                TreeQuery::Duplicates => duplicates = Some(duplicate_groups(&roots)),
+            }
+        }
+        CargoTreeResult {
+            command,
+            tree,
+            paths,
+            duplicates,
+        }
+    }
+}
+
+/// The deepest levels of the tree go first, then roots, paths and duplicates from the end
+impl ToolResponse for CargoTreeResult {
+    fn command_mut(&mut self) -> &mut CargoCommandJsonResult {
+        &mut self.command
+    }
+
+    fn trim_parts(&mut self, limit: usize, truncation: &mut Truncation) {
+        if let Some(tree) = &mut self.tree {
+            let mut depth = tree.iter().map(TreeNode::depth).max().unwrap_or(0);
+            while depth > 0 && json_len(tree) > limit {
+                depth -= 1;
+                for root in tree.iter_mut() {
+                    truncation.elided_entries += root.prune(depth);
+                }
+            }
+            truncation.elided_entries += keep_within(tree, limit);
+        }
+        if let Some(paths) = &mut self.paths {
+            truncation.elided_entries += keep_within(paths, limit);
+        }
+        if let Some(duplicates) = &mut self.duplicates {
+            truncation.elided_entries += keep_within(duplicates, limit);
+        }
+    }
+}
+
+/// Parse `cargo tree --prefix depth` output, where each line is a depth followed by a node
+fn parse_tree(stdout: &str) -> Vec<TreeNode> {
+    // The nodes on the path to the current line, which still collect children
+    let mut stack: Vec<TreeNode> = Vec::new();
+    let mut roots = Vec::new();
+
+    fn pop_into(stack: &mut Vec<TreeNode>, roots: &mut Vec<TreeNode>) {
+        let node = stack.pop().expect("stack is not empty");
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(node),
+            None => roots.push(node),
+        }
+    }
+
+    for line in stdout.lines() {
+        let digits = line
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(line.len());
+        let Ok(depth) = line[..digits].parse::<usize>() else {
+            continue;
+        };
+        while stack.len() > depth {
+            pop_into(&mut stack, &mut roots);
+        }
+        stack.push(parse_node(&line[digits..]));
+    }
+    while !stack.is_empty() {
+        pop_into(&mut stack, &mut roots);
+    }
+    roots
+}
+
+/// Parse `serde v1.0.0 (proc-macro) (*)` or `serde feature "derive"`
+fn parse_node(text: &str) -> TreeNode {
+    let (text, deduplicated) = match text.strip_suffix(" (*)") {
+        Some(text) => (text, true),
+        None => (text, false),
+    };
+    let (name, rest) = text.split_once(' ').unwrap_or((text, ""));
+    let feature = rest
+        .strip_prefix("feature \"")
+        .and_then(|f| f.strip_suffix('"'))
+        .map(str::to_string);
+    let version = rest
+        .strip_prefix('v')
+        .map(|v| v.split(' ').next().unwrap_or(v).to_string());
+    TreeNode {
+        name: name.to_string(),
+        version,
+        feature,
+        deduplicated,
+        children: vec![],
+    }
+}
+
+/// Maps each node of an inverted tree to the nodes that depend on it. Deduplicated nodes
+/// are not expanded again, but their edges were all listed where they first appeared.
+fn dependents(roots: &[TreeNode]) -> BTreeMap<String, BTreeSet<String>> {
+    fn walk(node: &TreeNode, map: &mut BTreeMap<String, BTreeSet<String>>) {
+        let entry = map.entry(node.label()).or_default();
+        entry.extend(node.children.iter().map(TreeNode::label));
+        for child in &node.children {
+            walk(child, map);
+        }
+    }
+    let mut map = BTreeMap::new();
+    for root in roots {
+        walk(root, &mut map);
+    }
+    map
+}
+
+/// Turn an inverted tree (`cargo tree --invert`) into paths from the packages nothing
+/// depends on (the workspace members) down to the inverted package
+fn paths_to_roots(roots: &[TreeNode]) -> Vec<Vec<String>> {
+    fn walk(
+        path: &mut Vec<String>,
+        dependents: &BTreeMap<String, BTreeSet<String>>,
+        paths: &mut Vec<Vec<String>>,
+    ) {
+        if paths.len() >= MAX_PATHS {
+            return;
+        }
+        let last = path.last().expect("path is not empty");
+        let next: Vec<_> = dependents
+            .get(last)
+            .into_iter()
+            .flatten()
+            // Dev-dependencies can form cycles
+            .filter(|d| !path.contains(d))
+            .cloned()
+            .collect();
+        if next.is_empty() {
+            paths.push(path.iter().rev().cloned().collect());
+            return;
+        }
+        for dependent in next {
+            path.push(dependent);
+            walk(path, dependents, paths);
+            path.pop();
+        }
+    }
+
+    let dependents = dependents(roots);
+    let mut paths = Vec::new();
+    for root in roots {
+        walk(&mut vec![root.label()], &dependents, &mut paths);
+    }
+    paths
+}
+
+/// Group the roots of `cargo tree --duplicates` output by package name
+fn duplicate_groups(roots: &[TreeNode]) -> Vec<DuplicateGroup> {
+    let dependents = dependents(roots);
+    let mut groups: BTreeMap<&str, Vec<DuplicateVersion>> = BTreeMap::new();
+    for root in roots {
+        groups
+            .entry(&root.name)
+            .or_default()
+            .push(DuplicateVersion {
+                version: root.version.clone().unwrap_or_default(),
+                dependents: dependents
+                    .get(&root.label())
+                    .into_iter()
+                    .flatten()
+                    .cloned()
+                    .collect(),
+            });
+    }
+    groups
+        .into_iter()
+        .map(|(name, versions)| DuplicateGroup {
+            name: name.to_string(),
+            versions,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cargo_command::{OutputBudget, fit_to_budget};
+
+    #[test]
+    fn test_parse_tree() {
+        let roots = parse_tree(indoc::indoc! {r#"
+            0app v0.1.0 (/p)
+            1serde feature "derive"
+            2serde v1.0.0
+            3serde_derive v1.0.0 (proc-macro)
+            1serde v1.0.0 (*)
+        "#});
+        assert_eq!(roots.len(), 1);
+        let app = &roots[0];
+        assert_eq!(app.version.as_deref(), Some("0.1.0"));
+        assert_eq!(app.children[0].label(), "serde feature \"derive\"");
+        assert_eq!(
+            app.children[0].children[0].children[0].label(),
+            "serde_derive v1.0.0"
+        );
+        assert!(app.children[1].deduplicated);
+    }
+
+    #[test]
+    fn test_why_paths() {
+        let roots = parse_tree(indoc::indoc! {"
+            0syn v2.0.0
+            1serde_derive v1.0.0 (proc-macro)
+            2serde v1.0.0
+            3app v0.1.0 (/p)
+            3lib v0.1.0 (/p/lib)
+            4app v0.1.0 (/p)
+            1thiserror-impl v1.0.0 (proc-macro)
+            2serde v1.0.0 (*)
+        "});
+        assert_eq!(
+            paths_to_roots(&roots),
+            [
+                vec![
+                    "app v0.1.0",
+                    "serde v1.0.0",
+                    "serde_derive v1.0.0",
+                    "syn v2.0.0"
+                ],
+                vec![
+                    "app v0.1.0",
+                    "lib v0.1.0",
+                    "serde v1.0.0",
+                    "serde_derive v1.0.0",
+                    "syn v2.0.0"
+                ],
+                vec![
+                    "app v0.1.0",
+                    "serde v1.0.0",
+                    "thiserror-impl v1.0.0",
+                    "syn v2.0.0"
+                ],
+                vec![
+                    "app v0.1.0",
+                    "lib v0.1.0",
+                    "serde v1.0.0",
+                    "thiserror-impl v1.0.0",
+                    "syn v2.0.0"
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let roots = parse_tree(indoc::indoc! {"
+            0getrandom v0.2.16
+            1ring v0.17.14
+
+            0getrandom v0.3.4
+            1rand_core v0.9.3
+        "});
+        assert_eq!(
+            duplicate_groups(&roots),
+            [DuplicateGroup {
+                name: "getrandom".to_string(),
+                versions: vec![
+                    DuplicateVersion {
+                        version: "0.2.16".to_string(),
+                        dependents: vec!["ring v0.17.14".to_string()],
+                    },
+                    DuplicateVersion {
+                        version: "0.3.4".to_string(),
+                        dependents: vec!["rand_core v0.9.3".to_string()],
+                    },
+                ],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_fit_to_budget() {
+        let mut stdout = "0app v0.1.0 (/p)\n".to_string();
+        for i in 0..20 {
+            stdout.push_str(&format!("1dep{i} v1.0.0\n"));
+            for j in 0..10 {
+                stdout.push_str(&format!("2dep{i}_{j} v1.0.0\n"));
+            }
+        }
+        let command = CargoCommandJsonResult {
+            stdout,
+            ..CargoCommandJsonResult::for_test("cargo tree", vec![])
+        };
+        let mut result = CargoTreeResult::new(command, TreeQuery::Deps);
+        fit_to_budget(
+            &mut result,
+            &OutputBudget {
+                max_bytes: Some(2_000),
+                max_tokens: None,
+            },
+        );
+        assert!(serde_json::to_string(&result).unwrap().len() <= 2_000);
+        let tree = result.tree.unwrap();
+        assert_eq!(tree[0].children.len(), 20);
+        assert_eq!(tree[0].depth(), 1);
+        assert_eq!(result.command.truncated.unwrap().elided_entries, 200);
+    }
+}
@@ -1,8 +1,15 @@
 use anyhow::Result;
 use expect_test::expect;
+use sacp::schema::{
+    InitializeRequest, PermissionOptionKind, ProtocolVersion, RequestPermissionOutcome,
+    RequestPermissionRequest, RequestPermissionResponse, SelectedPermissionOutcome,
+    SessionNotification, SessionUpdate,
+};
+use sacp::util::MatchMessage;
+use sacp::{ClientToAgent, JrConnectionCx, SessionMessage};
 use sacp_conductor::{Conductor, ProxiesAndAgent};
 use std::path::PathBuf;
-use symposium_cargo::CargoProxy;
+use symposium_cargo::{CargoProxy, ToolPolicy};
 
 fn get_test_project_path() -> PathBuf {
     PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/test-project")
@@ -21,30 +28,107 @@ fn setup_tracing() {
 }
 
 async fn run_cargo_tool(tool: &str) -> Result<String> {
+    run_cargo_tool_with(tool, "").await
+}
+
+/// Like `run_cargo_tool`, with `extra` appended to the tool's JSON arguments
+async fn run_cargo_tool_with(tool: &str, extra: &str) -> Result<String> {
+    run_cargo_tool_through(CargoProxy::new(), tool, extra).await
+}
+
+/// Like `run_cargo_tool_with`, through a proxy set up by the test
+async fn run_cargo_tool_through(proxy: CargoProxy, tool: &str, extra: &str) -> Result<String> {
     setup_tracing();
-    let proxy = CargoProxy;
     let test_project = get_test_project_path();
 
-    Ok(yopo::prompt(
+    let response = yopo::prompt(
         Conductor::new_agent(
             "test-conductor".to_string(),
             ProxiesAndAgent::new(elizacp::ElizaAgent::new()).proxy(proxy),
             Default::default(),
         ),
         &format!(
-            r#"Use tool cargo-mcp::{} with {{"cwd": "{}"}}"#,
+            r#"Use tool cargo-mcp::{} with {{"cwd": "{}"{}}}"#,
             tool,
-            test_project.display()
+            test_project.display(),
+            extra
         ),
     )
-    .await?)
+    .await?;
+
+    // Keep expectations independent of where the repository is checked out
+    Ok(response.replace(&test_project.display().to_string(), "$TEST_PROJECT"))
+}
+
+/// Like `run_cargo_tool_with`, answering the proxy's permission requests with the option of
+/// `kind` rather than allowing them like yopo does. Also returns the titles of the requests.
+async fn run_cargo_tool_answering(
+    tool: &str,
+    extra: &str,
+    kind: PermissionOptionKind,
+) -> Result<(String, Vec<String>)> {
+    setup_tracing();
+    let test_project = get_test_project_path();
+    let prompt = format!(
+        r#"Use tool cargo-mcp::{} with {{"cwd": "{}"{}}}"#,
+        tool,
+        test_project.display(),
+        extra
+    );
+    let conductor = Conductor::new_agent(
+        "test-conductor".to_string(),
+        ProxiesAndAgent::new(elizacp::ElizaAgent::new()).proxy(CargoProxy::new()),
+        Default::default(),
+    );
+
+    let (response, asked) = ClientToAgent::builder()
+        .connect_to(conductor)?
+        .run_until(async move |cx: JrConnectionCx<ClientToAgent>| {
+            cx.send_request(InitializeRequest::new(ProtocolVersion::LATEST))
+                .block_task()
+                .await?;
+            let mut session = cx
+                .build_session(PathBuf::from("."))
+                .block_task()
+                .start_session()
+                .await?;
+            session.send_prompt(prompt)?;
+
+            let (mut response, mut asked) = (String::new(), Vec::new());
+            while let SessionMessage::SessionMessage(message) = session.read_update().await? {
+                MatchMessage::new(message)
+                    .if_notification(async |notification: SessionNotification| {
+                        if let SessionUpdate::AgentMessageChunk(chunk) = notification.update {
+                            response.push_str(&yopo::content_block_to_string(&chunk.content));
+                        }
+                        Ok(())
+                    })
+                    .await
+                    .if_request(async |request: RequestPermissionRequest, request_cx| {
+                        asked.extend(request.tool_call.fields.title);
+                        let option = request.options.iter().find(|o| o.kind == kind).unwrap();
+                        let selected = SelectedPermissionOutcome::new(option.option_id.clone());
+                        request_cx.respond(RequestPermissionResponse::new(
+                            RequestPermissionOutcome::Selected(selected),
+                        ))
+                    })
+                    .await
+                    .otherwise(async |_| Ok(()))
+                    .await?;
+            }
+            Ok((response, asked))
+        })
+        .await?;
+
+    let response = response.replace(&test_project.display().to_string(), "$TEST_PROJECT");
+    Ok((response, asked))
 }
 
 #[tokio::test]
 async fn test_cargo_check() -> Result<()> {
     let response = run_cargo_tool("cargo_check").await?;
 
-    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":false,\"command\":\"cargo check  --message-format json\",\"exit_code\":101,\"messages\":[\"error[E0425]: cannot find value `error` in this scope\\n --> src/main.rs:2:5\\n  |\\n2 |     error\\n  |     ^^^^^ not found in this scope\\n\\n\",\"For more information about this error, try `rustc --explain E0425`.\\n\"],\"stderr\":\"    Checking test-project v0.1.0 (/home/gh-jackh726/symposium/symposium-cargo/tests/test-project)\\nerror: could not compile `test-project` (bin \\\"test-project\\\") due to 1 previous error\"}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(false), "command": String("cargo check  --message-format json"), "exit_code": Number(101), "messages": Array [String("error[E0425]: cannot find value `error` in this scope\n --> src/main.rs:2:5\n  |\n2 |     error\n  |     ^^^^^ not found in this scope\n\n"), String("For more information about this error, try `rustc --explain E0425`.\n")], "stderr": String("    Checking test-project v0.1.0 (/home/gh-jackh726/symposium/symposium-cargo/tests/test-project)\nerror: could not compile `test-project` (bin \"test-project\") due to 1 previous error")}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":false,\"command\":\"cargo check --message-format json\",\"diagnostics\":[{\"code\":\"E0425\",\"level\":\"error\",\"message\":\"cannot find value `error` in this scope\",\"spans\":[{\"byte_end\":21,\"byte_start\":16,\"column_end\":10,\"column_start\":5,\"file\":\"src/main.rs\",\"is_primary\":true,\"label\":\"not found in this scope\",\"line_end\":2,\"line_start\":2}]},{\"level\":\"failure-note\",\"message\":\"For more information about this error, try `rustc --explain E0425`.\"}],\"exit_code\":101,\"messages\":[\"error[E0425]: cannot find value `error` in this scope\\n --> src/main.rs:2:5\\n  |\\n2 |     error\\n  |     ^^^^^ not found in this scope\\n\\n\",\"For more information about this error, try `rustc --explain E0425`.\\n\"],\"result_id\":1,\"status\":\"completed\",\"stderr\":\"    Checking test-project v0.1.0 ($TEST_PROJECT)\\nerror: could not compile `test-project` (bin \\\"test-project\\\") due to 1 previous error\"}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(false), "command": String("cargo check --message-format json"), "diagnostics": Array [Object {"code": String("E0425"), "level": String("error"), "message": String("cannot find value `error` in this scope"), "spans": Array [Object {"byte_end": Number(21), "byte_start": Number(16), "column_end": Number(10), "column_start": Number(5), "file": String("src/main.rs"), "is_primary": Bool(true), "label": String("not found in this scope"), "line_end": Number(2), "line_start": Number(2)}]}, Object {"level": String("failure-note"), "message": String("For more information about this error, try `rustc --explain E0425`.")}], "exit_code": Number(101), "messages": Array [String("error[E0425]: cannot find value `error` in this scope\n --> src/main.rs:2:5\n  |\n2 |     error\n  |     ^^^^^ not found in this scope\n\n"), String("For more information about this error, try `rustc --explain E0425`.\n")], "result_id": Number(1), "status": String("completed"), "stderr": String("    Checking test-project v0.1.0 ($TEST_PROJECT)\nerror: could not compile `test-project` (bin \"test-project\") due to 1 previous error")}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
 
     Ok(())
 }
@@ -53,7 +137,7 @@ async fn test_cargo_check() -> Result<()> {
 async fn test_cargo_build() -> Result<()> {
     let response = run_cargo_tool("cargo_build").await?;
 
-    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":false,\"command\":\"cargo build  --message-format json\",\"exit_code\":101,\"messages\":[\"error[E0425]: cannot find value `error` in this scope\\n --> src/main.rs:2:5\\n  |\\n2 |     error\\n  |     ^^^^^ not found in this scope\\n\\n\",\"For more information about this error, try `rustc --explain E0425`.\\n\"],\"stderr\":\"   Compiling test-project v0.1.0 (/home/gh-jackh726/symposium/symposium-cargo/tests/test-project)\\nerror: could not compile `test-project` (bin \\\"test-project\\\") due to 1 previous error\"}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(false), "command": String("cargo build  --message-format json"), "exit_code": Number(101), "messages": Array [String("error[E0425]: cannot find value `error` in this scope\n --> src/main.rs:2:5\n  |\n2 |     error\n  |     ^^^^^ not found in this scope\n\n"), String("For more information about this error, try `rustc --explain E0425`.\n")], "stderr": String("   Compiling test-project v0.1.0 (/home/gh-jackh726/symposium/symposium-cargo/tests/test-project)\nerror: could not compile `test-project` (bin \"test-project\") due to 1 previous error")}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":false,\"command\":\"cargo build --message-format json\",\"diagnostics\":[{\"code\":\"E0425\",\"level\":\"error\",\"message\":\"cannot find value `error` in this scope\",\"spans\":[{\"byte_end\":21,\"byte_start\":16,\"column_end\":10,\"column_start\":5,\"file\":\"src/main.rs\",\"is_primary\":true,\"label\":\"not found in this scope\",\"line_end\":2,\"line_start\":2}]},{\"level\":\"failure-note\",\"message\":\"For more information about this error, try `rustc --explain E0425`.\"}],\"exit_code\":101,\"messages\":[\"error[E0425]: cannot find value `error` in this scope\\n --> src/main.rs:2:5\\n  |\\n2 |     error\\n  |     ^^^^^ not found in this scope\\n\\n\",\"For more information about this error, try `rustc --explain E0425`.\\n\"],\"result_id\":1,\"status\":\"completed\",\"stderr\":\"   Compiling test-project v0.1.0 ($TEST_PROJECT)\\nerror: could not compile `test-project` (bin \\\"test-project\\\") due to 1 previous error\"}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(false), "command": String("cargo build --message-format json"), "diagnostics": Array [Object {"code": String("E0425"), "level": String("error"), "message": String("cannot find value `error` in this scope"), "spans": Array [Object {"byte_end": Number(21), "byte_start": Number(16), "column_end": Number(10), "column_start": Number(5), "file": String("src/main.rs"), "is_primary": Bool(true), "label": String("not found in this scope"), "line_end": Number(2), "line_start": Number(2)}]}, Object {"level": String("failure-note"), "message": String("For more information about this error, try `rustc --explain E0425`.")}], "exit_code": Number(101), "messages": Array [String("error[E0425]: cannot find value `error` in this scope\n --> src/main.rs:2:5\n  |\n2 |     error\n  |     ^^^^^ not found in this scope\n\n"), String("For more information about this error, try `rustc --explain E0425`.\n")], "result_id": Number(1), "status": String("completed"), "stderr": String("   Compiling test-project v0.1.0 ($TEST_PROJECT)\nerror: could not compile `test-project` (bin \"test-project\") due to 1 previous error")}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
 
     Ok(())
 }
@@ -61,7 +145,325 @@ async fn test_cargo_build() -> Result<()> {
 async fn test_cargo_test() -> Result<()> {
     let response = run_cargo_tool("cargo_test").await?;
 
-    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":false,\"command\":\"cargo test  --message-format json\",\"exit_code\":101,\"messages\":[\"error[E0425]: cannot find value `error` in this scope\\n --> src/main.rs:2:5\\n  |\\n2 |     error\\n  |     ^^^^^ not found in this scope\\n\\n\",\"For more information about this error, try `rustc --explain E0425`.\\n\"],\"stderr\":\"   Compiling test-project v0.1.0 (/home/gh-jackh726/symposium/symposium-cargo/tests/test-project)\\nerror: could not compile `test-project` (bin \\\"test-project\\\" test) due to 1 previous error\"}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(false), "command": String("cargo test  --message-format json"), "exit_code": Number(101), "messages": Array [String("error[E0425]: cannot find value `error` in this scope\n --> src/main.rs:2:5\n  |\n2 |     error\n  |     ^^^^^ not found in this scope\n\n"), String("For more information about this error, try `rustc --explain E0425`.\n")], "stderr": String("   Compiling test-project v0.1.0 (/home/gh-jackh726/symposium/symposium-cargo/tests/test-project)\nerror: could not compile `test-project` (bin \"test-project\" test) due to 1 previous error")}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":false,\"command\":\"cargo test --message-format json\",\"diagnostics\":[{\"code\":\"E0425\",\"level\":\"error\",\"message\":\"cannot find value `error` in this scope\",\"spans\":[{\"byte_end\":21,\"byte_start\":16,\"column_end\":10,\"column_start\":5,\"file\":\"src/main.rs\",\"is_primary\":true,\"label\":\"not found in this scope\",\"line_end\":2,\"line_start\":2}]},{\"level\":\"failure-note\",\"message\":\"For more information about this error, try `rustc --explain E0425`.\"}],\"exit_code\":101,\"messages\":[\"error[E0425]: cannot find value `error` in this scope\\n --> src/main.rs:2:5\\n  |\\n2 |     error\\n  |     ^^^^^ not found in this scope\\n\\n\",\"For more information about this error, try `rustc --explain E0425`.\\n\"],\"result_id\":1,\"status\":\"completed\",\"stderr\":\"   Compiling test-project v0.1.0 ($TEST_PROJECT)\\nerror: could not compile `test-project` (bin \\\"test-project\\\" test) due to 1 previous error\",\"summary\":{\"failed\":0,\"ignored\":0,\"passed\":0},\"tests\":[]}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(false), "command": String("cargo test --message-format json"), "diagnostics": Array [Object {"code": String("E0425"), "level": String("error"), "message": String("cannot find value `error` in this scope"), "spans": Array [Object {"byte_end": Number(21), "byte_start": Number(16), "column_end": Number(10), "column_start": Number(5), "file": String("src/main.rs"), "is_primary": Bool(true), "label": String("not found in this scope"), "line_end": Number(2), "line_start": Number(2)}]}, Object {"level": String("failure-note"), "message": String("For more information about this error, try `rustc --explain E0425`.")}], "exit_code": Number(101), "messages": Array [String("error[E0425]: cannot find value `error` in this scope\n --> src/main.rs:2:5\n  |\n2 |     error\n  |     ^^^^^ not found in this scope\n\n"), String("For more information about this error, try `rustc --explain E0425`.\n")], "result_id": Number(1), "status": String("completed"), "stderr": String("   Compiling test-project v0.1.0 ($TEST_PROJECT)\nerror: could not compile `test-project` (bin \"test-project\" test) due to 1 previous error"), "summary": Object {"failed": Number(0), "ignored": Number(0), "passed": Number(0)}, "tests": Array []}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cargo_clippy() -> Result<()> {
+    let response = run_cargo_tool("cargo_clippy").await?;
+
+    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":false,\"command\":\"cargo clippy --message-format json\",\"diagnostics\":[{\"code\":\"E0425\",\"level\":\"error\",\"message\":\"cannot find value `error` in this scope\",\"spans\":[{\"byte_end\":21,\"byte_start\":16,\"column_end\":10,\"column_start\":5,\"file\":\"src/main.rs\",\"is_primary\":true,\"label\":\"not found in this scope\",\"line_end\":2,\"line_start\":2}]},{\"level\":\"failure-note\",\"message\":\"For more information about this error, try `rustc --explain E0425`.\"}],\"exit_code\":101,\"messages\":[\"error[E0425]: cannot find value `error` in this scope\\n --> src/main.rs:2:5\\n  |\\n2 |     error\\n  |     ^^^^^ not found in this scope\\n\\n\",\"For more information about this error, try `rustc --explain E0425`.\\n\"],\"result_id\":1,\"status\":\"completed\",\"stderr\":\"    Checking test-project v0.1.0 ($TEST_PROJECT)\\nerror: could not compile `test-project` (bin \\\"test-project\\\") due to 1 previous error\"}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(false), "command": String("cargo clippy --message-format json"), "diagnostics": Array [Object {"code": String("E0425"), "level": String("error"), "message": String("cannot find value `error` in this scope"), "spans": Array [Object {"byte_end": Number(21), "byte_start": Number(16), "column_end": Number(10), "column_start": Number(5), "file": String("src/main.rs"), "is_primary": Bool(true), "label": String("not found in this scope"), "line_end": Number(2), "line_start": Number(2)}]}, Object {"level": String("failure-note"), "message": String("For more information about this error, try `rustc --explain E0425`.")}], "exit_code": Number(101), "messages": Array [String("error[E0425]: cannot find value `error` in this scope\n --> src/main.rs:2:5\n  |\n2 |     error\n  |     ^^^^^ not found in this scope\n\n"), String("For more information about this error, try `rustc --explain E0425`.\n")], "result_id": Number(1), "status": String("completed"), "stderr": String("    Checking test-project v0.1.0 ($TEST_PROJECT)\nerror: could not compile `test-project` (bin \"test-project\") due to 1 previous error")}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cargo_metadata() -> Result<()> {
+    let response = run_cargo_tool("cargo_metadata").await?;
+
+    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":true,\"command\":\"cargo metadata --format-version 1 --offline --no-deps\",\"diagnostics\":[],\"exit_code\":0,\"messages\":[],\"result_id\":1,\"status\":\"completed\",\"stderr\":\"\",\"workspace\":{\"members\":[{\"dependencies\":[],\"manifest_path\":\"$TEST_PROJECT/Cargo.toml\",\"name\":\"test-project\",\"targets\":[{\"kind\":[\"bin\"],\"name\":\"test-project\",\"src_path\":\"$TEST_PROJECT/src/main.rs\"}],\"version\":\"0.1.0\"}],\"workspace_root\":\"$TEST_PROJECT\"}}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(true), "command": String("cargo metadata --format-version 1 --offline --no-deps"), "diagnostics": Array [], "exit_code": Number(0), "messages": Array [], "result_id": Number(1), "status": String("completed"), "stderr": String(""), "workspace": Object {"members": Array [Object {"dependencies": Array [], "manifest_path": String("$TEST_PROJECT/Cargo.toml"), "name": String("test-project"), "targets": Array [Object {"kind": Array [String("bin")], "name": String("test-project"), "src_path": String("$TEST_PROJECT/src/main.rs")}], "version": String("0.1.0")}], "workspace_root": String("$TEST_PROJECT")}}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cargo_check_unknown_feature() -> Result<()> {
+    let response = run_cargo_tool_with("cargo_check", r#", "features": ["nope"]"#).await?;
+
+    expect![[r#"ERROR: Mcp error: -32603: Internal error("feature `nope` is not declared by the selected packages")"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cargo_check_explain() -> Result<()> {
+    let response = run_cargo_tool_with("cargo_check", r#", "explain": true"#).await?;
+
+    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":false,\"command\":\"cargo check --message-format json\",\"diagnostics\":[{\"code\":\"E0425\",\"level\":\"error\",\"message\":\"cannot find value `error` in this scope\",\"spans\":[{\"byte_end\":21,\"byte_start\":16,\"column_end\":10,\"column_start\":5,\"file\":\"src/main.rs\",\"is_primary\":true,\"label\":\"not found in this scope\",\"line_end\":2,\"line_start\":2}]},{\"level\":\"failure-note\",\"message\":\"For more information about this error, try `rustc --explain E0425`.\"}],\"exit_code\":101,\"explanations\":{\"E0425\":\"An unresolved name was used.\"},\"messages\":[\"error[E0425]: cannot find value `error` in this scope\\n --> src/main.rs:2:5\\n  |\\n2 |     error\\n  |     ^^^^^ not found in this scope\\n\\n\",\"For more information about this error, try `rustc --explain E0425`.\\n\"],\"result_id\":1,\"status\":\"completed\",\"stderr\":\"    Checking test-project v0.1.0 ($TEST_PROJECT)\\nerror: could not compile `test-project` (bin \\\"test-project\\\") due to 1 previous error\"}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(false), "command": String("cargo check --message-format json"), "diagnostics": Array [Object {"code": String("E0425"), "level": String("error"), "message": String("cannot find value `error` in this scope"), "spans": Array [Object {"byte_end": Number(21), "byte_start": Number(16), "column_end": Number(10), "column_start": Number(5), "file": String("src/main.rs"), "is_primary": Bool(true), "label": String("not found in this scope"), "line_end": Number(2), "line_start": Number(2)}]}, Object {"level": String("failure-note"), "message": String("For more information about this error, try `rustc --explain E0425`.")}], "exit_code": Number(101), "explanations": Object {"E0425": String("An unresolved name was used.")}, "messages": Array [String("error[E0425]: cannot find value `error` in this scope\n --> src/main.rs:2:5\n  |\n2 |     error\n  |     ^^^^^ not found in this scope\n\n"), String("For more information about this error, try `rustc --explain E0425`.\n")], "result_id": Number(1), "status": String("completed"), "stderr": String("    Checking test-project v0.1.0 ($TEST_PROJECT)\nerror: could not compile `test-project` (bin \"test-project\") due to 1 previous error")}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_capture_baseline() -> Result<()> {
+    let response = run_cargo_tool("capture_baseline").await?;
+
+    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"command\":\"cargo check --message-format json\",\"diagnostics\":2}", meta: None }), annotations: None }], structured_content: Some(Object {"command": String("cargo check --message-format json"), "diagnostics": Number(2)}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_get_cargo_result_unknown_id() -> Result<()> {
+    let response = run_cargo_tool_with("get_cargo_result", r#", "result_id": 7"#).await?;
+
+    expect![[r#"ERROR: Mcp error: -32603: Internal error("no result 7; only the results of the last 20 commands are kept")"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_apply_suggestions_without_check() -> Result<()> {
+    let response = run_cargo_tool("cargo_apply_suggestions").await?;
+
+    expect![[r#"ERROR: Mcp error: -32603: Internal error("no suggestions to apply; run `cargo_check` or `cargo_clippy` first")"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tool_policy_disabled() -> Result<()> {
+    let policy = ToolPolicy {
+        disabled: vec!["cargo_clean".to_string()],
+        ..ToolPolicy::default()
+    };
+    let proxy = CargoProxy::new().tool_policy(policy);
+    let response = run_cargo_tool_through(proxy, "cargo_clean", "").await?;
+
+    expect!["ERROR: Mcp error: -32602: tool `cargo_clean` not found"].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tool_policy_confirm() -> Result<()> {
+    // yopo allows every permission request, so the tool runs once the user was asked
+    let policy = ToolPolicy {
+        confirm: vec!["get_cargo_result".to_string()],
+        ..ToolPolicy::default()
+    };
+    let proxy = CargoProxy::new().tool_policy(policy);
+    let response = run_cargo_tool_through(proxy, "get_cargo_result", r#", "result_id": 7"#).await?;
+
+    expect![[r#"ERROR: Mcp error: -32603: Internal error("no result 7; only the results of the last 20 commands are kept")"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cargo_update_asks_permission() -> Result<()> {
+    let (response, asked) = run_cargo_tool_answering(
+        "cargo_update",
+        r#", "extra_args": ["--dry-run"]"#,
+        PermissionOptionKind::AllowOnce,
+    )
+    .await?;
+
+    expect!["cargo update"].assert_eq(&asked.join("\n"));
+    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"build_success\":true,\"command\":\"cargo update --dry-run\",\"diagnostics\":[],\"exit_code\":0,\"messages\":[],\"result_id\":1,\"status\":\"completed\",\"stderr\":\"     Locking 0 packages to latest compatible versions\\nwarning: not updating lockfile due to dry run\"}", meta: None }), annotations: None }], structured_content: Some(Object {"build_success": Bool(true), "command": String("cargo update --dry-run"), "diagnostics": Array [], "exit_code": Number(0), "messages": Array [], "result_id": Number(1), "status": String("completed"), "stderr": String("     Locking 0 packages to latest compatible versions\nwarning: not updating lockfile due to dry run")}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cargo_update_rejected_by_user() -> Result<()> {
+    let (response, asked) = run_cargo_tool_answering(
+        "cargo_update",
+        r#", "package": "serde""#,
+        PermissionOptionKind::RejectOnce,
+    )
+    .await?;
+
+    expect!["cargo update serde"].assert_eq(&asked.join("\n"));
+    expect![[r#"ERROR: Mcp error: -32603: Internal error("the user did not allow `cargo_update` to run")"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cargo_update_rejects_config() -> Result<()> {
+    // Arguments are checked before the user is asked about them
+    let (response, asked) = run_cargo_tool_answering(
+        "cargo_update",
+        r#", "extra_args": ["--config", "build.rustc-wrapper='sh'"]"#,
+        PermissionOptionKind::AllowOnce,
+    )
+    .await?;
+
+    assert!(asked.is_empty());
+    expect![[r#"ERROR: Mcp error: -32602: `--config` is never passed on by `cargo_update`; allowed flags: --manifest-path, --offline, --locked, --frozen, --quiet, -q, --verbose, -v, --package, -p, --workspace, -w, --recursive, --precise, --dry-run, -n, --ignore-rust-version({"allowed":["--manifest-path","--offline","--locked","--frozen","--quiet","-q","--verbose","-v","--package","-p","--workspace","-w","--recursive","--precise","--dry-run","-n","--ignore-rust-version"],"arg":"--config","rejection":"forbidden","tool":"cargo_update"})"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cargo_update_rejects_flag_as_package() -> Result<()> {
+    // `package` becomes `-p <package>`, and cargo would read this one as `--config`
+    let (response, asked) = run_cargo_tool_answering(
+        "cargo_update",
+        r#", "package": "--config=build.target-dir='/elsewhere'""#,
+        PermissionOptionKind::AllowOnce,
+    )
+    .await?;
+
+    assert!(asked.is_empty());
+    expect![[r#"ERROR: Mcp error: -32602: `-p` has a value that starts with `-`; allowed flags: --manifest-path, --offline, --locked, --frozen, --quiet, -q, --verbose, -v, --package, -p, --workspace, -w, --recursive, --precise, --dry-run, -n, --ignore-rust-version({"allowed":["--manifest-path","--offline","--locked","--frozen","--quiet","-q","--verbose","-v","--package","-p","--workspace","-w","--recursive","--precise","--dry-run","-n","--ignore-rust-version"],"arg":"-p","rejection":"flag_as_value","tool":"cargo_update"})"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_set_cwd_defaults_to_session_cwd() -> Result<()> {
+    setup_tracing();
+
+    // yopo creates its session in `.`, which the proxy should pick up from `session/new`
+    let response = yopo::prompt(
+        Conductor::new_agent(
+            "test-conductor".to_string(),
+            ProxiesAndAgent::new(elizacp::ElizaAgent::new()).proxy(CargoProxy::new()),
+            Default::default(),
+        ),
+        r#"Use tool cargo-mcp::set_cwd with {}"#,
+    )
+    .await?;
+
+    expect![[r#"OK: CallToolResult { content: [Annotated { raw: Text(RawTextContent { text: "{\"cwd\":\".\"}", meta: None }), annotations: None }], structured_content: Some(Object {"cwd": String(".")}), is_error: Some(false), meta: None }"#]].assert_eq(&response);
+
+    Ok(())
+}
+
+/// A `--mcp` server, spoken to over its stdin and stdout
+struct StandaloneServer {
+    _process: tokio::process::Child,
+    stdin: tokio::process::ChildStdin,
+    stdout: tokio::io::Lines<tokio::io::BufReader<tokio::process::ChildStdout>>,
+    /// Responses read while waiting for another one, by id
+    responses: std::collections::BTreeMap<u64, serde_json::Value>,
+}
+
+impl StandaloneServer {
+    /// Start a server in `cwd` and initialize it
+    async fn start(cwd: &std::path::Path) -> Result<Self> {
+        use tokio::io::AsyncBufReadExt;
+
+        let mut process = tokio::process::Command::new(env!("CARGO_BIN_EXE_symposium-cargo"))
+            .arg("--mcp")
+            .current_dir(cwd)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let mut server = StandaloneServer {
+            stdin: process.stdin.take().unwrap(),
+            stdout: tokio::io::BufReader::new(process.stdout.take().unwrap()).lines(),
+            _process: process,
+            responses: Default::default(),
+        };
+        server.send(r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{"protocolVersion":"2025-06-18","capabilities":{},"clientInfo":{"name":"test","version":"0"}}}"#).await?;
+        server.response(1).await?;
+        server
+            .send(r#"{"jsonrpc":"2.0","method":"notifications/initialized"}"#)
+            .await?;
+        Ok(server)
+    }
+
+    async fn send(&mut self, message: &str) -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        self.stdin
+            .write_all(format!("{message}\n").as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    /// Wait for the response to request `id`. Requests are answered concurrently, so responses
+    /// may come back in any order.
+    async fn response(&mut self, id: u64) -> Result<serde_json::Value> {
+        while !self.responses.contains_key(&id) {
+            let line = self.stdout.next_line().await?.expect("server exited early");
+            let response: serde_json::Value = serde_json::from_str(&line)?;
+            if let Some(id) = response["id"].as_u64() {
+                self.responses.insert(id, response);
+            }
+        }
+        Ok(self.responses.remove(&id).unwrap())
+    }
+}
+
+#[tokio::test]
+async fn test_standalone_mcp_server() -> Result<()> {
+    let test_project = get_test_project_path();
+    let mut server = StandaloneServer::start(&test_project).await?;
+    server
+        .send(r#"{"jsonrpc":"2.0","id":2,"method":"tools/list"}"#)
+        .await?;
+    server.send(r#"{"jsonrpc":"2.0","id":3,"method":"tools/call","params":{"name":"set_cwd","arguments":{}}}"#).await?;
+
+    let cwd = server.response(3).await?;
+    let tools = server.response(2).await?;
+    let tools: Vec<_> = tools["result"]["tools"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|tool| tool["name"].as_str().unwrap())
+        .collect();
+    expect!["set_cwd set_toolchain capture_baseline cargo_check cargo_build cargo_test cargo_clippy cargo_fmt cargo_metadata cargo_tree cargo_add cargo_clean cargo_remove cargo_run cargo_update rustc_explain cargo_apply_suggestions get_cargo_result"].assert_eq(&tools.join(" "));
+
+    // The session's directory is the one the server was started in
+    let cwd = cwd["result"]["structuredContent"]["cwd"]
+        .as_str()
+        .unwrap()
+        .replace(&test_project.display().to_string(), "$TEST_PROJECT");
+    expect!["$TEST_PROJECT"].assert_eq(&cwd);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_standalone_mcp_server_broken_project_config() -> Result<()> {
+    let project = std::env::temp_dir().join(format!("broken-config-{}", std::process::id()));
+    std::fs::create_dir_all(project.join(".symposium"))?;
+    std::fs::write(project.join(".symposium/cargo.toml"), "[tools\n")?;
+
+    // The server starts, and the tools that read the configuration report the error
+    let mut server = StandaloneServer::start(&project).await?;
+    server.send(r#"{"jsonrpc":"2.0","id":2,"method":"tools/call","params":{"name":"cargo_check","arguments":{}}}"#).await?;
+    let response = server.response(2).await?;
+    let message = response["error"]["data"]
+        .as_str()
+        .unwrap_or_default()
+        .replace(&project.display().to_string(), "$PROJECT");
+    std::fs::remove_dir_all(&project)?;
+    expect!["failed to parse $PROJECT/.symposium/cargo.toml"].assert_eq(&message);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_standalone_mcp_server_cancel() -> Result<()> {
+    let project = std::env::temp_dir().join(format!("cancel-{}", std::process::id()));
+    std::fs::create_dir_all(project.join("src"))?;
+    std::fs::write(
+        project.join("Cargo.toml"),
+        "[package]\nname = \"sleeper\"\nedition = \"2021\"\n\n[workspace]\n",
+    )?;
+    std::fs::write(
+        project.join("src/main.rs"),
+        indoc::indoc! {r#"
+            fn main() {
+                std::fs::write("started", "").unwrap();
+                std::thread::sleep(std::time::Duration::from_secs(3));
+                std::fs::write("finished", "").unwrap();
+            }
+        "#},
+    )?;
+
+    let mut server = StandaloneServer::start(&project).await?;
+    server.send(r#"{"jsonrpc":"2.0","id":2,"method":"tools/call","params":{"name":"cargo_run","arguments":{}}}"#).await?;
+    while !project.join("started").exists() {
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+
+    // Cancelling the request kills cargo and the program it runs
+    server
+        .send(r#"{"jsonrpc":"2.0","method":"notifications/cancelled","params":{"requestId":2}}"#)
+        .await?;
+    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    let finished = project.join("finished").exists();
+    std::fs::remove_dir_all(&project)?;
+    assert!(!finished, "the program ran to completion");
 
     Ok(())
 }